@@ -0,0 +1,225 @@
+use std::borrow::Cow;
+use std::iter;
+use std::ops::Range;
+
+use lsp_types::Position;
+use ropey::Rope;
+
+/// Text the diff engines and the document store work on.
+///
+/// Indices count chars unless their name says otherwise.
+/// Lines end after `\n`, `\r\n` or a lone `\r`, like in LSP. The last line is what follows the last
+/// line break, possibly nothing.
+/// LSP positions count UTF-16 code units within their line.
+pub trait TextBuffer: Clone {
+    fn from_text(text: &str) -> Self;
+    fn len_chars(&self) -> usize;
+    fn len_bytes(&self) -> usize;
+    fn len_lines(&self) -> usize;
+    fn line_to_char(&self, line: usize) -> usize;
+    fn char_to_line(&self, char_idx: usize) -> usize;
+    fn char_to_byte(&self, char_idx: usize) -> usize;
+    fn byte_to_char(&self, byte_idx: usize) -> usize;
+    /// The text of `chars`, borrowed where the buffer stores it contiguously.
+    fn slice(&self, chars: Range<usize>) -> Cow<'_, str>;
+    /// The text of `chars` as the contiguous pieces the buffer stores it in.
+    fn chunks(&self, chars: Range<usize>) -> Box<dyn Iterator<Item = &str> + '_>;
+    fn insert(&mut self, char_idx: usize, text: &str);
+    fn remove(&mut self, chars: Range<usize>);
+
+    /// `line` including its line break.
+    fn line(&self, line: usize) -> Cow<'_, str> {
+        let start = self.line_to_char(line);
+        let end = if line + 1 < self.len_lines() {
+            self.line_to_char(line + 1)
+        } else {
+            self.len_chars()
+        };
+        self.slice(start..end)
+    }
+
+    fn text(&self) -> Cow<'_, str> {
+        self.slice(0..self.len_chars())
+    }
+
+    /// The char index of an LSP position.
+    /// Like the spec says, a character past the end of the line means the end of the line.
+    fn position_to_char(&self, p: Position) -> usize {
+        let line = self.line(p.line as usize);
+        let content = line.trim_end_matches(['\n', '\r']);
+        let mut units = 0;
+        let column = content
+            .chars()
+            .take_while(|c| {
+                units += c.len_utf16();
+                units <= p.character as usize
+            })
+            .count();
+        self.line_to_char(p.line as usize) + column
+    }
+
+    fn char_to_position(&self, char_idx: usize) -> Position {
+        let line = self.char_to_line(char_idx);
+        let start = self.line_to_char(line);
+        Position::new(line as u64, utf16_len(&self.slice(start..char_idx)) as u64)
+    }
+}
+
+/// Length of `s` in UTF-16 code units, the unit of LSP columns.
+pub fn utf16_len(s: &str) -> usize {
+    s.encode_utf16().count()
+}
+
+/// Ropey is built without `unicode_lines`, so Unicode line and paragraph separators don't end lines.
+impl TextBuffer for Rope {
+    fn from_text(text: &str) -> Self {
+        Rope::from_str(text)
+    }
+
+    fn len_chars(&self) -> usize {
+        Rope::len_chars(self)
+    }
+
+    fn len_bytes(&self) -> usize {
+        Rope::len_bytes(self)
+    }
+
+    fn len_lines(&self) -> usize {
+        Rope::len_lines(self)
+    }
+
+    fn line_to_char(&self, line: usize) -> usize {
+        Rope::line_to_char(self, line)
+    }
+
+    fn char_to_line(&self, char_idx: usize) -> usize {
+        Rope::char_to_line(self, char_idx)
+    }
+
+    fn char_to_byte(&self, char_idx: usize) -> usize {
+        Rope::char_to_byte(self, char_idx)
+    }
+
+    fn byte_to_char(&self, byte_idx: usize) -> usize {
+        Rope::byte_to_char(self, byte_idx)
+    }
+
+    fn slice(&self, chars: Range<usize>) -> Cow<'_, str> {
+        Rope::slice(self, chars).into()
+    }
+
+    fn chunks(&self, chars: Range<usize>) -> Box<dyn Iterator<Item = &str> + '_> {
+        Box::new(Rope::slice(self, chars).chunks())
+    }
+
+    fn insert(&mut self, char_idx: usize, text: &str) {
+        Rope::insert(self, char_idx, text)
+    }
+
+    fn remove(&mut self, chars: Range<usize>) {
+        Rope::remove(self, chars)
+    }
+}
+
+/// Byte indices just past the line breaks in `text`.
+pub fn line_ends(text: &str) -> impl Iterator<Item = usize> + '_ {
+    let bytes = text.as_bytes();
+    bytes.iter().enumerate().filter_map(move |(i, &b)| match b {
+        b'\n' => Some(i + 1),
+        b'\r' if bytes.get(i + 1) != Some(&b'\n') => Some(i + 1),
+        _ => None,
+    })
+}
+
+/// Simple rather than fast, most operations scan the string.
+impl TextBuffer for String {
+    fn from_text(text: &str) -> Self {
+        text.to_owned()
+    }
+
+    fn len_chars(&self) -> usize {
+        self.chars().count()
+    }
+
+    fn len_bytes(&self) -> usize {
+        self.len()
+    }
+
+    fn len_lines(&self) -> usize {
+        line_ends(self).count() + 1
+    }
+
+    fn line_to_char(&self, line: usize) -> usize {
+        let byte = match line {
+            0 => 0,
+            _ => line_ends(self).nth(line - 1).unwrap_or(self.len()),
+        };
+        self.byte_to_char(byte)
+    }
+
+    fn char_to_line(&self, char_idx: usize) -> usize {
+        // The `\n` of a `\r\n` is on the line the `\r` is.
+        let byte = self.char_to_byte(char_idx);
+        line_ends(self).take_while(|&end| end <= byte).count()
+    }
+
+    fn char_to_byte(&self, char_idx: usize) -> usize {
+        self.char_indices()
+            .nth(char_idx)
+            .map_or(self.len(), |(i, _)| i)
+    }
+
+    fn byte_to_char(&self, byte_idx: usize) -> usize {
+        self[..byte_idx].chars().count()
+    }
+
+    fn slice(&self, chars: Range<usize>) -> Cow<'_, str> {
+        Cow::Borrowed(&self[self.char_to_byte(chars.start)..self.char_to_byte(chars.end)])
+    }
+
+    fn chunks(&self, chars: Range<usize>) -> Box<dyn Iterator<Item = &str> + '_> {
+        Box::new(iter::once(
+            &self[self.char_to_byte(chars.start)..self.char_to_byte(chars.end)],
+        ))
+    }
+
+    fn insert(&mut self, char_idx: usize, text: &str) {
+        let byte = self.char_to_byte(char_idx);
+        self.insert_str(byte, text)
+    }
+
+    fn remove(&mut self, chars: Range<usize>) {
+        let bytes = self.char_to_byte(chars.start)..self.char_to_byte(chars.end);
+        self.replace_range(bytes, "")
+    }
+}
+
+#[test]
+fn positions_test() {
+    let text = "a😀b\r\nxyz\nc\rd\u{2028}e";
+    let rope = Rope::from_text(text);
+    let string = String::from_text(text);
+    assert_eq!(rope.len_lines(), 4);
+    assert_eq!(string.len_lines(), 4);
+    assert_eq!(crate::rope_diff::lines(text).len(), 4);
+    for &(line, character, char_idx) in &[
+        (0, 3, 2),
+        (0, 9, 3),
+        (1, 1, 6),
+        (2, 0, 9),
+        (2, 5, 10),
+        (3, 2, 13),
+        (3, 9, 14),
+    ] {
+        let p = Position::new(line, character);
+        assert_eq!(rope.position_to_char(p), char_idx);
+        assert_eq!(string.position_to_char(p), char_idx);
+    }
+    for char_idx in 0..=rope.len_chars() {
+        assert_eq!(rope.char_to_line(char_idx), string.char_to_line(char_idx));
+    }
+    assert_eq!(string.char_to_position(2), Position::new(0, 3));
+    for line in 0..4 {
+        assert_eq!(rope.line(line), string.line(line));
+    }
+}
@@ -1,209 +1,219 @@
-use std::ops::Index;
-
 use diffs::{myers, Diff, Replace};
 use lsp_types::{Position, Range, TextDocumentContentChangeEvent};
-use ropey::str_utils::count_line_breaks;
 use smallvec::SmallVec;
 
+use crate::buffer::{line_ends, utf16_len, TextBuffer};
+
+/// The changes a content change is split into, in the order they apply.
 pub type Changes = SmallVec<[TextDocumentContentChangeEvent; 12]>;
-#[derive(Debug, Clone)]
-pub struct RopeSlice<'a> {
-    pub slice: ropey::RopeSlice<'a>,
-    /// The line index that this slice was created at.
-    pub absolute_pos: ropey::Position,
-}
 
+/// Splits a ranged change into character level deletions, insertions and replacements.
 pub struct Incremental<'o, 'n> {
     changes: Changes,
-    old: RopeSlice<'o>,
+    /// The text the change replaces.
+    old: &'o str,
+    /// Byte offsets in `old` of its units, the diff's indices, followed by its length.
+    old_bytes: Vec<usize>,
+    /// Byte offsets in `old` where its lines start.
+    line_starts: Vec<usize>,
     new: &'n str,
+    /// Byte offsets in `new` of its units, followed by its length.
+    new_bytes: Vec<usize>,
+    /// Added to the column of an `old` position on `on_line` to get its column after the changes so far.
     char_offset: isize,
+    /// Added to the line of an `old` position to get its line after the changes so far.
     line_offset: isize,
     on_line: usize,
+    /// Whether positions account for the changes before them. If not they are all in `old`.
+    shift: bool,
 }
 
 impl<'o, 'n> Incremental<'o, 'n> {
-    pub fn diff(old: RopeSlice<'o>, new: &'n str) -> Changes {
+    /// Changes that turn the text in `range` of `old` into `new`, positioned in the whole document.
+    /// `range` must lie within `old`.
+    pub fn diff<B: TextBuffer>(old: &B, range: Range, new: &str) -> Changes {
+        Incremental::run(old, range, new, true)
+    }
+
+    /// Like `diff`, but every change is positioned in `old`, the way `TextEdit`s are.
+    pub fn edits<B: TextBuffer>(old: &B, range: Range, new: &str) -> Changes {
+        Incremental::run(old, range, new, false)
+    }
+
+    fn run<B: TextBuffer>(old: &B, range: Range, new: &str, shift: bool) -> Changes {
+        let start = old.position_to_char(range.start);
+        let end = old.position_to_char(range.end);
+
+        // Most of a large change is usually text that was already there.
+        // Trimming it chunk by chunk keeps Myers and the copy below to the part that differs.
+        let old_len = old.char_to_byte(end) - old.char_to_byte(start);
+        let mut prefix = common_prefix(old.chunks(start..end), new.as_bytes());
+        if prefix == old_len && prefix == new.len() {
+            return SmallVec::new();
+        }
+        while !new.is_char_boundary(prefix) {
+            prefix -= 1;
+        }
+        // A `\r\n` is a single line break, neither end of the trimmed text may fall inside one.
+        let old_after = start + new[..prefix].chars().count();
+        if new[..prefix].ends_with('\r')
+            && (new[prefix..].starts_with('\n') || char_at(old, old_after, end) == Some('\n'))
+        {
+            prefix -= 1;
+        }
+        let chunks: Vec<_> = old.chunks(start..end).collect();
+        let max_suffix = old_len.min(new.len()) - prefix;
+        let mut suffix = common_suffix(chunks.into_iter().rev(), new.as_bytes()).min(max_suffix);
+        while !new.is_char_boundary(new.len() - suffix) {
+            suffix -= 1;
+        }
+        let old_before = (end - new[new.len() - suffix..].chars().count()).checked_sub(1);
+        if new[new.len() - suffix..].starts_with('\n')
+            && (new[..new.len() - suffix].ends_with('\r')
+                || old_before.and_then(|i| char_at(old, i, end)) == Some('\r'))
+        {
+            suffix -= 1;
+        }
+        let start = start + new[..prefix].chars().count();
+        let end = end - new[new.len() - suffix..].chars().count();
+        let new = &new[prefix..new.len() - suffix];
+
+        // Flat, so Myers indexes a slice rather than walking the buffer.
+        let text = old.slice(start..end);
+        // The client's column may be past the end of the line.
+        let absolute_pos = old.char_to_position(start);
+
+        let line_starts = Some(0).into_iter().chain(line_ends(&text)).collect();
+        // Diffing units rather than bytes keeps every change to whole chars and line breaks.
+        let old_bytes = unit_bytes(&text);
+        let new_bytes = unit_bytes(new);
+        let old_units = units(&text, &old_bytes);
+        let new_units = units(new, &new_bytes);
         let mut cd = Incremental {
             new,
-            old: old.clone(),
+            new_bytes,
+            old: &text,
+            old_bytes,
+            line_starts,
             changes: SmallVec::with_capacity(10),
-            line_offset: old.absolute_pos.line as isize,
-            char_offset: old.absolute_pos.character as isize,
+            line_offset: absolute_pos.line as isize,
+            char_offset: absolute_pos.character as isize,
             on_line: 0,
+            shift,
         };
 
         myers::diff(
             &mut Replace::new(&mut cd),
-            &old.slice,
+            &old_units[..],
             0,
-            old.slice.len_bytes(),
-            &Str(new),
+            old_units.len(),
+            &new_units[..],
             0,
-            new.len(),
+            new_units.len(),
         )
         .unwrap();
         cd.changes
     }
-}
 
-struct Str<'l>(&'l str);
-impl<'l> Index<usize> for Str<'l> {
-    type Output = u8;
-    fn index(&self, idx: usize) -> &u8 {
-        self.0.as_bytes().get(idx).unwrap()
+    /// Line and UTF-16 column in `old` of the char at `byte`.
+    fn old_position(&self, byte: usize) -> (usize, usize) {
+        let line = match self.line_starts.binary_search(&byte) {
+            Ok(line) => line,
+            Err(next) => next - 1,
+        };
+        (line, utf16_len(&self.old[self.line_starts[line]..byte]))
     }
-}
-
-impl<'o, 'n> Diff for Incremental<'o, 'n> {
-    type Error = ();
-    fn delete(&mut self, old: usize, len: usize) -> Result<(), Self::Error> {
-        dbg!("\n\n DELETE");
-        dbg!(old);
-        dbg!(len);
-        dbg!(self.char_offset);
-        let start = self.old.slice.byte_to_position(old);
 
+    /// Where the char at `byte` in `old` is after the changes so far.
+    fn position(&mut self, byte: usize) -> Position {
+        let (line, character) = self.old_position(byte);
         // This requires `old` indices to be ordered.
-        if start.line != self.on_line {
-            self.on_line = start.line;
+        if line != self.on_line {
+            self.on_line = line;
             self.char_offset = 0;
         };
+        Position::new(
+            (self.line_offset + line as isize) as u64,
+            (self.char_offset + character as isize) as u64,
+        )
+    }
 
-        debug_assert_eq!(self.old.slice.byte_to_line(old), start.line);
-        dbg!(
-            String::from(self.old.slice.line(self.old.slice.byte_to_line(old)))
-                .split_at(start.character as usize)
-        );
-
-        let start = Position::new(
-            dbg!(self.line_offset + start.line as isize) as u64,
-            dbg!(self.char_offset + start.character as isize) as u64,
-        );
-
-        let end = self.old.slice.byte_to_position(old + len);
-
-        debug_assert_eq!(self.old.slice.byte_to_line(old + len), end.line);
-
-        dbg!(
-            String::from(self.old.slice.line(self.old.slice.byte_to_line(old + len)))
-                .split_at(end.character as usize)
-        );
-
-        if end.line != self.on_line {
-            self.on_line = end.line;
-            self.char_offset = 0;
+    /// Moves the offsets past `text`, which replaced everything from `start` to the old
+    /// `end_column` on `on_line`.
+    fn advance(&mut self, start: Position, end: Position, end_column: usize, text: &str) {
+        if !self.shift {
+            return;
+        }
+        // foo\nbar\nbuzz
+        // replace: {line: 1, char: 2} .. {line: 2, char: 3} = "r\nbuz" with "lol\nz"
+        // foo\nbalol\nzz
+        // self.line_offset += 1 - (2 - 1); // == 0
+        // self.char_offset = "z".len() - 3; // == -2
+        // "foo\nbar\nbuzz"[{line: 2, char: 3}] == 'z'
+        // "foo\nbalol\nzz"[{line: 2 + 0, char: 3 + (-2)}] == 'z'
+        let new_lines = line_ends(text).count() as isize;
+        self.line_offset += new_lines - (end.line - start.line) as isize;
+        self.char_offset = match line_ends(text).last() {
+            Some(i) => utf16_len(&text[i..]) as isize - end_column as isize,
+            None => start.character as isize + utf16_len(text) as isize - end_column as isize,
         };
+    }
 
-        let end = Position::new(
-            dbg!(self.line_offset + end.line as isize) as u64,
-            dbg!(self.char_offset + end.character as isize) as u64,
-        );
+    fn new_text(&self, new: usize, new_len: usize) -> &'n str {
+        &self.new[self.new_bytes[new]..self.new_bytes[new + new_len]]
+    }
+}
 
-        self.changes.push(dbg!(TextDocumentContentChangeEvent {
+impl<'o, 'n> Diff for Incremental<'o, 'n> {
+    type Error = ();
+    fn delete(&mut self, old: usize, len: usize) -> Result<(), Self::Error> {
+        let start = self.position(self.old_bytes[old]);
+        let end_byte = self.old_bytes[old + len];
+        let end = self.position(end_byte);
+        let (_, end_column) = self.old_position(end_byte);
+
+        self.changes.push(TextDocumentContentChangeEvent {
             range: Some(Range { start, end }),
             range_length: None,
             text: "".to_owned(),
-        }));
-
-        // foo\nbar\nbuzz
-        // delete: {line: 1, char: 2} .. {line: 2, char: 3} = "r\nbuz"
-        // foo\nbaz
-        // self.line_offset -= 2 - 1; // == -1
-        // self.char_offset -= 3 - 2; // == -1
-        //
-        // "foo\nbar\nbuzz"[11] == 'z'; // {line: 2 char: 3}
-        // "foo\nbaz"[{line: 2 + (-1), char: 3 + (-1)}] == 'z'
-
-        // foobarbazz
-        // delete: {line: 0, char: 3} ..{line: 0, char: 6}
-        // foobazz
-        // self.line_offset -= 0 - 0;
-        // self.char_offset -= 6 - 3; // == -3
-        // "foobazz"[{line: 0 + 0, char: 7 + (-3)}] == 'a'
-
-        self.line_offset -= (end.line - start.line) as isize;
-        self.char_offset -= end.character as isize - start.character as isize;
-
+        });
+        log::trace!(
+            "delete old[{}..{}] offsets ({}, {}): {:?}..{:?}",
+            old,
+            old + len,
+            self.line_offset,
+            self.char_offset,
+            start,
+            end
+        );
+        self.advance(start, end, end_column, "");
         Ok(())
     }
 
     fn insert(&mut self, old: usize, new: usize, new_len: usize) -> Result<(), Self::Error> {
-        dbg!("\n\n INSERT");
-        dbg!(old);
-        dbg!(new);
-        dbg!(new_len);
-        dbg!(self.char_offset);
-        let start = self.old.slice.byte_to_position(old);
+        let start = self.position(self.old_bytes[old]);
+        let (_, column) = self.old_position(self.old_bytes[old]);
+        let text = self.new_text(new, new_len);
 
-        // This requires `old` indices to be ordered.
-        if start.line != self.on_line {
-            self.on_line = start.line;
-            self.char_offset = 0;
-        };
-
-        debug_assert_eq!(self.old.slice.byte_to_line(old), start.line);
-        dbg!(
-            String::from(self.old.slice.line(self.old.slice.byte_to_line(old)))
-                .split_at(start.character as usize)
-        );
-
-        let start = Position::new(
-            dbg!(self.line_offset + start.line as isize) as u64,
-            dbg!(self.char_offset + start.character as isize) as u64,
-        );
-
-        let new = pre_char_boundary(self.new, new);
-        let new_end = pre_char_boundary(self.new, new + new_len);
-
-        let text = &self.new[new..new_end];
-
-        self.changes.push(dbg!(TextDocumentContentChangeEvent {
+        self.changes.push(TextDocumentContentChangeEvent {
             range: Some(Range { start, end: start }),
             range_length: Some(0),
-            text: dbg!(text.to_owned()),
-        }));
-
-        // foo\nbar\nbuzz
-        // insert: {line: 1, char: 2} "rrr"
-        // foo\nbarrrr\nbuzz
-        // self.line_offset += count_lines("rrr"); // == 0
-        // if count_lines("rrr") > 0 { // false
-        //   self.char_offset = "rrr".lines().last().len() - 2; // == 1
-        // } else {
-        //   self.char_offset += "rrr".len(); // == 3
-        // }
-        //
-        // "foo\nbar\nbuzz"[7] == '\n'; // {line: 1 char: 3}
-        // "foo\nbarrrr\nbuzz"[{line: 1 + 0, char: 3 + 3}] == '\n'
-
-        // foobarbazz
-        // insert: {line: 0, char: 3} "\nfo"
-        // foo\nfobarbazz
-        // self.line_offset += count_lines("\nfo"); // == 1
-        // if count_lines("\nfo") > 0 { // true
-        //   self.char_offset = "\nfo".lines().last().len() - 3; // == -1
-        // } else {
-        //   self.char_offset += "\nfo".len(); // == 3
-        // }
-        //
-        // "foobarbazz"[4] == 'a'; // {line: 0 char: 4}
-        // "foo\nfobarbazz"[{line: 0 + 1, char: 4 + (-1)}] == 'a'
-        //
-        // "foobarbazz"[9] == 'z'; // {line: 0 char: 9}
-        // "foo\nfobarbazz"[{line: 0 + 1, char: 9 + (-1)}] == 'z'
-
-        let new_line_count = count_line_breaks(text) as isize;
-        self.line_offset += new_line_count;
-        if new_line_count > 0 {
-            // false
-            self.char_offset =
-                text.lines().rev().next().unwrap().len() as isize - start.line as isize;
-        } else {
-            self.char_offset += text.len() as isize;
-        };
+            text: text.to_owned(),
+        });
+        log::trace!(
+            "insert new[{}..{}] at old[{}] offsets ({}, {}): {:?} {:?}",
+            new,
+            new + new_len,
+            old,
+            self.line_offset,
+            self.char_offset,
+            start,
+            text
+        );
+        self.advance(start, start, column, text);
         Ok(())
     }
+
     fn replace(
         &mut self,
         old: usize,
@@ -211,116 +221,140 @@ impl<'o, 'n> Diff for Incremental<'o, 'n> {
         new: usize,
         new_len: usize,
     ) -> Result<(), Self::Error> {
-        dbg!("REPLACE");
-        dbg!(old);
-        dbg!(old_len);
-        dbg!(new);
-        dbg!(new_len);
-        dbg!(self.char_offset);
-
-        let start = self.old.slice.byte_to_position(old);
-
-        // This requires `old` indices to be ordered.
-        if start.line != self.on_line {
-            self.on_line = start.line;
-            self.char_offset = 0;
-        };
-
-        dbg!(
-            String::from(self.old.slice.line(self.old.slice.byte_to_line(old)))
-                .split_at(start.character as usize)
-        );
-
-        let start = Position::new(
-            dbg!(self.line_offset + start.line as isize) as u64,
-            dbg!(self.char_offset + start.character as isize) as u64,
-        );
-        let end = self.old.slice.byte_to_position(old + old_len);
-
-        dbg!(String::from(
-            self.old
-                .slice
-                .line(self.old.slice.byte_to_line(old + old_len))
-        )
-        .split_at(end.character as usize));
-
-        if end.line != self.on_line {
-            self.on_line = end.line;
-            self.char_offset = 0;
-        };
-
-        let end = Position::new(
-            dbg!(self.line_offset + end.line as isize) as u64,
-            dbg!(self.char_offset + end.character as isize) as u64,
-        );
-
-        let new = pre_char_boundary(self.new, new);
-        let new_end = pre_char_boundary(self.new, new + new_len);
-
-        let text = &self.new[new..new_end];
+        let start = self.position(self.old_bytes[old]);
+        let end_byte = self.old_bytes[old + old_len];
+        let end = self.position(end_byte);
+        let (_, end_column) = self.old_position(end_byte);
+        let text = self.new_text(new, new_len);
 
-        self.changes.push(dbg!(TextDocumentContentChangeEvent {
+        self.changes.push(TextDocumentContentChangeEvent {
             range: Some(Range { start, end }),
             range_length: None,
-            text: dbg!(text.to_owned()),
-        }));
+            text: text.to_owned(),
+        });
+        log::trace!(
+            "replace old[{}..{}] with new[{}..{}] offsets ({}, {}): {:?}..{:?} {:?}",
+            old,
+            old + old_len,
+            new,
+            new + new_len,
+            self.line_offset,
+            self.char_offset,
+            start,
+            end,
+            text
+        );
+        self.advance(start, end, end_column, text);
+        Ok(())
+    }
+}
 
-        // foo\nbar\nbuzz
-        // delete: {line: 1, char: 2} .. {line: 2, char: 3} = "r\nbuz"; insert "rrr"
-        // foo\nbarrrz
-        // self.line_offset += "rrr".lines().count() - (2 - 1); // == -1
-        // let char_delete_offset = ; // == -1
-        // if count_lines("rrr") > 0 { // false
-        //   self.char_offset = "rrr".lines().last().len() - 3; // == -6
-        // } else {
-        //   self.char_offset += "rrr".len() + 2 - 3; // == 2
-        // }
-        //
-        // "foo\nbar\nbuzz"[11] == 'z'; // {line: 2 char: 3}
-        // "foo\nbarrrz"[{line: 2 + (-1), char: 3 + 2}] == 'z'
+/// Length in bytes of the common prefix of `old`'s chunks and `new`.
+fn common_prefix<'a>(old: impl Iterator<Item = &'a str>, new: &[u8]) -> usize {
+    let mut len = 0;
+    for chunk in old {
+        let same = chunk
+            .bytes()
+            .zip(&new[len..])
+            .take_while(|&(a, &b)| a == b)
+            .count();
+        len += same;
+        if same < chunk.len() {
+            break;
+        }
+    }
+    len
+}
 
-        // foobarbazz
-        // delete: {line: 0, char: 3} .. {line: 0, char: 6} = "bar"; insert "lol\n"
-        // foolol\nbazz
-        // self.line_offset += "lol\n".lines().count() - (0 - 0); // == 1
-        // if count_lines("lol\n") > 0 { // true
-        //   self.char_offset = "lol\n".lines().last().len() - 6; // == -6
-        // } else {
-        //   self.char_offset += "lol\n".len() + 3 - 6; // == 1
-        // }
-        //
-        // foobarbazz[7] = 'a' {line 0, char: 7}
-        // "foolol\nbazz"[{line: 0 + 1, char: 7 +3 - 6 (-6)}] == 'a'
+/// Length in bytes of the common suffix of `new` and the chunks `old` yields back to front.
+fn common_suffix<'a>(old: impl Iterator<Item = &'a str>, new: &[u8]) -> usize {
+    let mut len = 0;
+    for chunk in old {
+        let same = chunk
+            .bytes()
+            .rev()
+            .zip(new[..new.len() - len].iter().rev())
+            .take_while(|&(a, &b)| a == b)
+            .count();
+        len += same;
+        if same < chunk.len() {
+            break;
+        }
+    }
+    len
+}
 
-        // foobarbazz
-        // delete: {line: 0, char: 3} .. {line: 03 - 6, char: 6} = "bar"; insert "lol\nz"
-        // foolol\nbazz
-        // self.line_offset += "lol\nz".lines().count() - (0 - 0); // == 1
-        // if count_lines("lol\nz") > 0 { // true
-        //   self.char_offset = "lol\nz".lines().last().len() - 6; // == -5
-        // } else {
-        //   self.char_offset += "lol\nz".len() + 3 - 6; // == 2
-        // }
-        //
-        // "foobarbazz"[7] = 'a' {line 0, char: 7}
-        // "foolol\nzbazz"[{line: 0 + 1, char: 7 + (-5)}] == 'a'
+/// The byte offset of each diff unit in `s`, followed by `s.len()`.
+/// A unit is a char, or a whole `\r\n`.
+fn unit_bytes(s: &str) -> Vec<usize> {
+    s.char_indices()
+        .map(|(i, _)| i)
+        .filter(|&i| !(s[..i].ends_with('\r') && s[i..].starts_with('\n')))
+        .chain(Some(s.len()))
+        .collect()
+}
 
-        let new_line_count = count_line_breaks(text) as isize;
-        self.line_offset += new_line_count;
-        if dbg!(new_line_count) > 0 {
-            self.char_offset =
-                dbg!(text.lines().rev().next().unwrap().len() as isize - end.character as isize);
-        } else {
-            self.char_offset +=
-                dbg!(text.len() as isize + start.character as isize - end.character as isize);
-        };
-        Ok(())
+/// The units of `s` that start at `bytes`, as returned by `unit_bytes`.
+fn units<'a>(s: &'a str, bytes: &[usize]) -> Vec<&'a str> {
+    bytes.windows(2).map(|w| &s[w[0]..w[1]]).collect()
+}
+
+/// The char at `char_idx` in `buffer`, if it is before `end`.
+fn char_at<B: TextBuffer>(buffer: &B, char_idx: usize, end: usize) -> Option<char> {
+    if char_idx < end {
+        buffer.slice(char_idx..char_idx + 1).chars().next()
+    } else {
+        None
     }
 }
 
-fn pre_char_boundary(s: &str, mut idx: usize) -> usize {
-    while !s.is_char_boundary(idx) {
-        idx -= 1
+#[test]
+fn trimmed_diff_test() {
+    let old = String::from("fn main() {\n    println!(\"hi\");\n}\n");
+    let range = Range::new(Position::new(0, 0), Position::new(3, 0));
+    let new = "fn main() {\n    println!(\"hello\");\n}\n";
+    let changes = Incremental::diff(&old, range, new);
+    assert_eq!(changes.len(), 1);
+    assert_eq!(
+        changes[0].range,
+        Some(Range::new(Position::new(1, 15), Position::new(1, 16)))
+    );
+    assert_eq!(changes[0].text, "ello");
+    assert!(Incremental::diff(&old, range, &old).is_empty());
+}
+
+#[test]
+fn crlf_diff_test() {
+    // The old text, the end of the replaced range, which starts at 0:0, and its new text.
+    let cases = [
+        ("abc\r\ndef", (1, 0), "xyz\n"),
+        ("a\r\nb", (1, 1), "a\nb"),
+        ("a\nb", (1, 1), "a\r\nb"),
+        ("a\r\nb\r\n", (2, 0), "a\r\r\nb\n"),
+        ("a\rb", (1, 1), "a\r\nb"),
+    ];
+    for &(old, (line, character), new) in cases.iter() {
+        let range = Range::new(Position::new(0, 0), Position::new(line, character));
+        let old = String::from(old);
+        let start = old.position_to_char(range.start);
+        let end = old.position_to_char(range.end);
+        let expected = format!("{}{}{}", &old[..start], new, &old[end..]);
+        for &shift in [true, false].iter() {
+            let changes = Incremental::run(&old, range, new, shift);
+            let text = if shift {
+                let mut text = old.clone();
+                for change in &changes {
+                    crate::apply_change(&mut text, change);
+                }
+                text
+            } else {
+                let edits: Vec<_> = changes
+                    .iter()
+                    .map(|c| lsp_types::TextEdit::new(c.range.unwrap(), c.text.clone()))
+                    .collect();
+                crate::apply_edits(&old, &edits)
+            };
+            assert_eq!(text, expected, "{:?} to {:?}", old, new);
+        }
     }
-    idx
 }
@@ -0,0 +1,250 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use std::collections::HashMap;
+
+use lsp_diff::Options;
+
+use crate::limits::Limits;
+use crate::logger::{self, Format};
+
+pub const USAGE: &str = "\
+Usage: lsp-diff [OPTIONS] -- <server> [args]
+
+Options:
+  --split <all|incremental|full|off>  Which changes to split (default all)
+  --algorithm <myers|patience>        Line diff used for full document changes (default myers)
+  --small-edit <chars>                Forward single line edits shorter than this as is (default 3)
+  --min-free-memory <percent>         Restart the server when free memory drops below this (default 10)
+  --max-crashes <n>                   Give up after n crashes in 3 minutes (default 5)
+  --hang-timeout <secs>               Restart a server silent this long with a request waiting (default 120)
+  --reissue                           Re-send idempotent requests to a restarted server
+  --workers <n>                       Threads splitting changes, 0 splits them as they are read (default 4)
+  --store-limit <size>                Drop texts of documents over 1M while open documents take more (default 1G)
+  --rlimit-as <size>                  Address space limit of the server, e.g. 4G
+  --rlimit-cpu <secs>                 CPU time limit of the server
+  --rlimit-nofile <n>                 Open file limit of the server
+  --cgroup <dir>                      Delegated cgroup v2 directory to run the server in
+  --cgroup-memory <size>              memory.max of --cgroup
+  --cgroup-cpus <n>                   cpu.max of --cgroup, in CPUs
+  --no-process-group                  Don't put the server in its own process group
+  --stderr-log <file>                 Where to write the server's stderr
+  --trace <file>                      Write every message lsp-diff receives or rewrites to file
+  --log-level <filter>                Level, optionally per module, e.g. info,lsp_diff::chars_diff=trace
+                                      Levels are off, error, warn (default), info, debug and trace
+  --log-file <file>                   Append the log to file instead of stderr
+  --log-format <text|json>            json writes one object per line (default text)
+  --listen <addr>                     Accept the client on a TCP address instead of stdio
+  --config <file>                     Config file to use
+  -h, --help";
+
+#[derive(Debug)]
+pub enum Transport {
+    Stdio,
+    Listen(String),
+}
+
+/// Split options given on the command line, these win over language profiles.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Given {
+    pub split: bool,
+    pub algorithm: bool,
+    pub small_edit: bool,
+}
+
+#[derive(Debug)]
+pub struct Args {
+    /// The server command followed by its arguments.
+    pub command: Vec<String>,
+    /// Extra environment variables for the server.
+    pub env: HashMap<String, String>,
+    /// Used for documents whose language has no profile.
+    pub options: Options,
+    /// Which of `options` were given on the command line.
+    pub given: Given,
+    /// Fraction of memory plus swap that must stay free.
+    pub min_free_memory: f64,
+    pub max_crashes: usize,
+    pub crash_window: Duration,
+    pub hang_timeout: Duration,
+    pub reissue: bool,
+    /// Threads splitting changes of different documents in parallel.
+    pub workers: usize,
+    /// Bytes the open documents may take up before large ones are dropped.
+    pub store_limit: usize,
+    pub limits: Limits,
+    pub stderr_log: Option<PathBuf>,
+    pub trace: Option<PathBuf>,
+    pub log_level: logger::Filter,
+    pub log_file: Option<PathBuf>,
+    pub log_format: Format,
+    pub transport: Transport,
+    pub config: Option<PathBuf>,
+}
+
+impl Default for Args {
+    fn default() -> Self {
+        Args {
+            command: Vec::new(),
+            env: HashMap::new(),
+            options: Options::default(),
+            given: Given::default(),
+            min_free_memory: 0.10,
+            max_crashes: 5,
+            crash_window: Duration::from_secs(180),
+            hang_timeout: Duration::from_secs(120),
+            reissue: false,
+            workers: 4,
+            store_limit: 1 << 30,
+            limits: Limits::default(),
+            stderr_log: None,
+            trace: None,
+            log_level: logger::Filter::default(),
+            log_file: None,
+            log_format: Format::Text,
+            transport: Transport::Stdio,
+            config: None,
+        }
+    }
+}
+
+impl Args {
+    /// Parses the arguments after the program name.
+    /// The first argument that isn't an option starts the server command, so `--` is optional.
+    pub fn parse(args: impl Iterator<Item = String>) -> Result<Args, String> {
+        Args::parse_onto(Args::default(), args)
+    }
+
+    /// Like `parse`, but options not given keep their value in `a`, e.g. from a config profile.
+    pub fn parse_onto(mut a: Args, mut args: impl Iterator<Item = String>) -> Result<Args, String> {
+        while let Some(arg) = args.next() {
+            let (flag, inline) = match arg.find('=') {
+                Some(i) if arg.starts_with("--") => {
+                    (arg[..i].to_owned(), Some(arg[i + 1..].to_owned()))
+                }
+                _ => (arg, None),
+            };
+
+            match flag.as_str() {
+                "--" => {
+                    a.command = args.collect();
+                    break;
+                }
+                "-h" | "--help" => return Err(USAGE.to_owned()),
+                "--split" => {
+                    a.options.split = value(&flag, inline, &mut args)?.parse()?;
+                    a.given.split = true;
+                }
+                "--algorithm" => {
+                    a.options.algorithm = value(&flag, inline, &mut args)?.parse()?;
+                    a.given.algorithm = true;
+                }
+                "--small-edit" => {
+                    a.options.small_edit = number(&flag, inline, &mut args)?;
+                    a.given.small_edit = true;
+                }
+                "--min-free-memory" => {
+                    a.min_free_memory = number::<f64>(&flag, inline, &mut args)? / 100.0
+                }
+                "--max-crashes" => a.max_crashes = number(&flag, inline, &mut args)?,
+                "--hang-timeout" => {
+                    a.hang_timeout = Duration::from_secs(number(&flag, inline, &mut args)?)
+                }
+                "--reissue" => a.reissue = true,
+                "--workers" => a.workers = number(&flag, inline, &mut args)?,
+                "--store-limit" => {
+                    a.store_limit = size(&value(&flag, inline, &mut args)?)? as usize
+                }
+                "--rlimit-as" => {
+                    a.limits.address_space = Some(size(&value(&flag, inline, &mut args)?)?)
+                }
+                "--rlimit-cpu" => a.limits.cpu_time = Some(number(&flag, inline, &mut args)?),
+                "--rlimit-nofile" => a.limits.open_files = Some(number(&flag, inline, &mut args)?),
+                "--cgroup" => a.limits.cgroup = Some(value(&flag, inline, &mut args)?.into()),
+                "--cgroup-memory" => {
+                    a.limits.cgroup_memory = Some(size(&value(&flag, inline, &mut args)?)?)
+                }
+                "--cgroup-cpus" => a.limits.cgroup_cpus = Some(number(&flag, inline, &mut args)?),
+                "--no-process-group" => a.limits.process_group = false,
+                "--stderr-log" => a.stderr_log = Some(value(&flag, inline, &mut args)?.into()),
+                "--trace" => a.trace = Some(value(&flag, inline, &mut args)?.into()),
+                "--log-level" => a.log_level = value(&flag, inline, &mut args)?.parse()?,
+                "--log-file" => a.log_file = Some(value(&flag, inline, &mut args)?.into()),
+                "--log-format" => a.log_format = value(&flag, inline, &mut args)?.parse()?,
+                "--listen" => a.transport = Transport::Listen(value(&flag, inline, &mut args)?),
+                "--config" => a.config = Some(value(&flag, inline, &mut args)?.into()),
+                _ if flag.starts_with('-') => return Err(format!("unknown option '{}'", flag)),
+                _ => {
+                    a.command = Some(flag).into_iter().chain(args).collect();
+                    break;
+                }
+            }
+        }
+
+        if a.command.is_empty() {
+            Err("Provide lsp command as first argument.".to_owned())
+        } else {
+            Ok(a)
+        }
+    }
+}
+
+fn value(
+    flag: &str,
+    inline: Option<String>,
+    args: &mut impl Iterator<Item = String>,
+) -> Result<String, String> {
+    inline
+        .or_else(|| args.next())
+        .ok_or_else(|| format!("{} needs a value", flag))
+}
+
+fn number<N: FromStr>(
+    flag: &str,
+    inline: Option<String>,
+    args: &mut impl Iterator<Item = String>,
+) -> Result<N, String> {
+    let v = value(flag, inline, args)?;
+    v.parse()
+        .map_err(|_| format!("{} expects a number, got '{}'", flag, v))
+}
+
+/// Parses a byte count with an optional K, M or G suffix.
+pub fn size(s: &str) -> Result<u64, String> {
+    let (digits, unit) = match s.chars().last() {
+        Some('K') | Some('k') => (&s[..s.len() - 1], 1 << 10),
+        Some('M') | Some('m') => (&s[..s.len() - 1], 1 << 20),
+        Some('G') | Some('g') => (&s[..s.len() - 1], 1 << 30),
+        _ => (s, 1),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(unit))
+        .ok_or_else(|| format!("'{}' is not a size", s))
+}
+
+#[test]
+fn parse_args_test() {
+    let args = |s: &str| Args::parse(s.split_whitespace().map(str::to_owned));
+
+    let a = args("--split=incremental --rlimit-as 2G -- hie-wrapper --lsp").unwrap();
+    assert_eq!(a.command, vec!["hie-wrapper", "--lsp"]);
+    assert_eq!(a.options.split, lsp_diff::Split::Incremental);
+    assert_eq!(a.limits.address_space, Some(2 << 30));
+
+    // The old `lsp-diff <server> [args]` form still works.
+    let a = args("rust-analyzer --log-level debug").unwrap();
+    assert_eq!(a.command, vec!["rust-analyzer", "--log-level", "debug"]);
+    assert_eq!(a.log_level, logger::Filter::default());
+
+    let a = args("--log-level=info,lsp_diff::chars_diff=trace --log-format json hie").unwrap();
+    assert_eq!(a.log_level.default, log::LevelFilter::Info);
+    assert_eq!(a.log_format, Format::Json);
+    assert!(args("--log-level=loud hie").is_err());
+
+    assert!(args("--store-limit 99999999999G hie").is_err());
+    assert!(args("--split").is_err());
+    assert!(args("--reissue").is_err());
+}
@@ -0,0 +1,214 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use lsp_types::Url;
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::rpc::{self, Envelope, Noti};
+
+pub const RESTART_SERVER: &str = "lsp-diff.restartServer";
+/// Optionally takes the uri of the document to resync, otherwise all are resynced.
+pub const RESYNC_DOCUMENT: &str = "lsp-diff.resyncDocument";
+pub const TOGGLE_SPLITTING: &str = "lsp-diff.toggleSplitting";
+/// A request of its own rather than a command, so its result reaches the client.
+pub const STATS: &str = "lsp-diff/stats";
+
+/// Commands lsp-diff handles itself instead of forwarding.
+pub const COMMANDS: &[&str] = &[RESTART_SERVER, RESYNC_DOCUMENT, TOGGLE_SPLITTING];
+
+/// Whether messages with `method` may be for lsp-diff rather than the server.
+pub fn handles(method: &str) -> bool {
+    method == "workspace/executeCommand" || method == STATS
+}
+
+#[derive(Debug)]
+pub enum Command {
+    RestartServer,
+    ResyncDocument(Option<Url>),
+    ToggleSplitting,
+    Stats,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "method", content = "params")]
+enum Exec {
+    #[serde(rename = "workspace/executeCommand")]
+    Exec {
+        command: String,
+        #[serde(default)]
+        arguments: Vec<Value>,
+    },
+}
+
+/// Returns the request id and command if `body` is a `workspace/executeCommand` for lsp-diff
+/// or a `lsp-diff/stats` request. The command errs if its arguments are invalid.
+pub fn parse(envelope: &Envelope, body: &[u8]) -> Option<(Value, Result<Command, String>)> {
+    let id = match *envelope {
+        Envelope {
+            id: Some(ref id),
+            method: Some(ref method),
+        } if method == "workspace/executeCommand" => id,
+        Envelope {
+            id: Some(ref id),
+            method: Some(ref method),
+        } if method == STATS => return Some((id.clone(), Ok(Command::Stats))),
+        _ => return None,
+    };
+
+    let Noti {
+        params: Exec::Exec { command, arguments },
+        ..
+    } = serde_json::from_slice(body).ok()?;
+
+    let command = match command.as_str() {
+        RESTART_SERVER => Ok(Command::RestartServer),
+        RESYNC_DOCUMENT => match arguments.into_iter().next() {
+            None => Ok(Command::ResyncDocument(None)),
+            Some(uri) => match uri.as_str().and_then(|uri| Url::parse(uri).ok()) {
+                Some(uri) => Ok(Command::ResyncDocument(Some(uri))),
+                None => Err(format!(
+                    "{} takes a document uri, not {}",
+                    RESYNC_DOCUMENT, uri
+                )),
+            },
+        },
+        TOGGLE_SPLITTING => Ok(Command::ToggleSplitting),
+        _ => return None,
+    };
+    Some((id.clone(), command))
+}
+
+/// Whether the server's save options include the text, which lsp-diff asks the client for
+/// either way. Shared with the thread forwarding server output, which sets it.
+#[derive(Clone, Default)]
+pub struct SaveText(Arc<AtomicBool>);
+
+impl SaveText {
+    /// Removes the text from a `didSave` the server didn't ask for it in.
+    /// The notification is returned with its header to be sent instead.
+    pub fn strip(&self, body: &[u8]) -> Option<Vec<u8>> {
+        if self.0.load(Ordering::SeqCst) {
+            return None;
+        }
+        let mut noti: Value = serde_json::from_slice(body).ok()?;
+        noti.get_mut("params")?.as_object_mut()?.remove("text")?;
+        let body = serde_json::to_vec(&noti).ok()?;
+        rpc::trace("to server", &body);
+        let mut msg = Vec::with_capacity(body.len() + 30);
+        rpc::write_msg(&mut msg, &body).ok()?;
+        Some(msg)
+    }
+}
+
+/// Adds `COMMANDS` to the `executeCommandProvider` of the server's `InitializeResult`,
+/// and has the client include the text in `didSave` if the server takes saves.
+/// Whether the server asked for the text itself is noted in `save_text`.
+/// Works on a `Value`, so capabilities newer than our lsp-types survive.
+pub fn advertise(resp: &[u8], save_text: &SaveText) -> Option<Vec<u8>> {
+    let mut resp: Value = serde_json::from_slice(resp).ok()?;
+    {
+        let caps = resp
+            .get_mut("result")?
+            .get_mut("capabilities")?
+            .as_object_mut()?;
+        let commands = caps
+            .entry("executeCommandProvider")
+            .or_insert_with(|| json!({}))
+            .as_object_mut()?
+            .entry("commands")
+            .or_insert_with(|| json!([]))
+            .as_array_mut()?;
+        commands.extend(COMMANDS.iter().map(|&c| Value::from(c)));
+        // With the text in `didSave` lsp-diff can check its copy on every save.
+        if let Some(save) = caps
+            .get_mut("textDocumentSync")
+            .and_then(Value::as_object_mut)
+            .and_then(|sync| sync.get_mut("save"))
+        {
+            match *save {
+                Value::Bool(false) => (),
+                Value::Object(ref mut options) => {
+                    let wanted = options.get("includeText") == Some(&Value::Bool(true));
+                    save_text.0.store(wanted, Ordering::SeqCst);
+                    options.insert("includeText".to_owned(), Value::Bool(true));
+                }
+                _ => *save = json!({ "includeText": true }),
+            }
+        }
+    }
+    serde_json::to_vec(&resp).ok()
+}
+
+#[test]
+fn parse_test() {
+    let resync = |arguments: Value| {
+        let body = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": "workspace/executeCommand",
+            "params": { "command": RESYNC_DOCUMENT, "arguments": arguments },
+        });
+        let envelope = serde_json::from_value(body.clone()).unwrap();
+        parse(&envelope, &serde_json::to_vec(&body).unwrap())
+            .unwrap()
+            .1
+    };
+    match resync(json!(["file:///a.rs"])) {
+        Ok(Command::ResyncDocument(Some(ref uri))) => assert_eq!(uri.as_str(), "file:///a.rs"),
+        r => panic!("{:?}", r),
+    }
+    assert!(resync(json!([])).is_ok());
+    assert!(resync(json!([1])).is_err());
+    assert!(resync(json!(["not a uri"])).is_err());
+}
+
+#[test]
+fn advertise_test() {
+    let resp = br#"{"jsonrpc":"2.0","id":0,"result":{"capabilities":{"executeCommandProvider":{"commands":["fix"]},"textDocumentSync":{"change":2,"save":true}}}}"#;
+    let save_text = SaveText::default();
+    let resp: Value = serde_json::from_slice(&advertise(resp, &save_text).unwrap()).unwrap();
+    assert_eq!(
+        resp["result"]["capabilities"]["executeCommandProvider"]["commands"],
+        json!(["fix", RESTART_SERVER, RESYNC_DOCUMENT, TOGGLE_SPLITTING])
+    );
+    assert_eq!(
+        resp["result"]["capabilities"]["textDocumentSync"]["save"],
+        json!({ "includeText": true })
+    );
+}
+
+#[test]
+fn save_text_test() {
+    let saved = |text: bool| {
+        let mut params = json!({ "textDocument": { "uri": "file:///a.rs" } });
+        if text {
+            params["text"] = json!("fn main() {}\n");
+        }
+        let noti = json!({ "jsonrpc": "2.0", "method": "textDocument/didSave", "params": params });
+        serde_json::to_vec(&noti).unwrap()
+    };
+    let init = |save: Value| {
+        let resp = json!({"jsonrpc": "2.0", "id": 0, "result": {"capabilities": {
+            "textDocumentSync": { "change": 2, "save": save }
+        }}});
+        let save_text = SaveText::default();
+        advertise(&serde_json::to_vec(&resp).unwrap(), &save_text).unwrap();
+        save_text
+    };
+
+    // The server only wants to hear of saves, the text lsp-diff asked for is dropped.
+    let save_text = init(json!(true));
+    let mut msg = &save_text.strip(&saved(true)).unwrap()[..];
+    let mut body = Vec::new();
+    assert!(rpc::read_msg(&mut msg, &mut body).unwrap());
+    assert_eq!(body, saved(false));
+    assert!(save_text.strip(&saved(false)).is_none());
+    assert!(init(json!({ "includeText": false }))
+        .strip(&saved(true))
+        .is_some());
+    // The server asked for the text itself.
+    assert!(init(json!({ "includeText": true }))
+        .strip(&saved(true))
+        .is_none());
+}
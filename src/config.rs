@@ -0,0 +1,200 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use lsp_types::InitializeParams;
+use serde::Deserialize;
+
+use lsp_diff::{Algorithm, Options, Split};
+
+use crate::cli::Args;
+
+/// Name of the config file in a workspace root.
+const WORKSPACE_CONFIG: &str = ".lsp-diff.toml";
+
+/// ```toml
+/// [[profile]]          # Neither `server` nor `language_id`, applies to everything.
+/// max_crashes = 3
+///
+/// [[profile]]
+/// server = "rust-analyzer"
+/// env = { RA_LOG = "error" }
+///
+/// [[profile]]
+/// language_id = "haskell"
+/// split = "incremental"
+/// ```
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default, rename = "profile")]
+    pub profiles: Vec<Profile>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    /// Matches the file name of the server command.
+    pub server: Option<String>,
+    pub language_id: Option<String>,
+    pub split: Option<Split>,
+    pub algorithm: Option<Algorithm>,
+    pub small_edit: Option<u64>,
+    /// Percent of memory plus swap that must stay free.
+    pub min_free_memory: Option<f64>,
+    /// Seconds.
+    pub hang_timeout: Option<u64>,
+    pub max_crashes: Option<usize>,
+    /// Seconds.
+    pub crash_window: Option<u64>,
+    pub reissue: Option<bool>,
+    pub workers: Option<usize>,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        toml::from_str(&text).map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Uses `explicit` if given, otherwise the first config found in the workspace root,
+    /// `$XDG_CONFIG_HOME/lsp-diff/config.toml` or `~/.config/lsp-diff/config.toml`.
+    /// No config at all is the empty config.
+    pub fn discover(explicit: Option<&Path>, init: &InitializeParams) -> Result<Config, String> {
+        if let Some(path) = explicit {
+            return Config::load(path);
+        }
+
+        let root = init
+            .root_uri
+            .as_ref()
+            .and_then(|uri| uri.to_file_path().ok())
+            .or_else(|| init.root_path.as_ref().map(PathBuf::from));
+        let user = env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")));
+
+        let candidates = root
+            .map(|root| root.join(WORKSPACE_CONFIG))
+            .into_iter()
+            .chain(user.map(|user| user.join("lsp-diff").join("config.toml")));
+        for path in candidates {
+            if path.is_file() {
+                return Config::load(&path);
+            }
+        }
+        Ok(Config::default())
+    }
+
+    /// Applies the profiles for all servers, then those for `command`.
+    pub fn apply(&self, command: &[String], args: &mut Args) {
+        let server = command.first().map(|c| {
+            Path::new(c)
+                .file_name()
+                .map_or(c.clone(), |name| name.to_string_lossy().into_owned())
+        });
+
+        let global = self
+            .profiles
+            .iter()
+            .filter(|p| p.server.is_none() && p.language_id.is_none());
+        let for_server = self
+            .profiles
+            .iter()
+            .filter(|p| p.language_id.is_none() && p.server.is_some() && p.server == server);
+        for profile in global.chain(for_server) {
+            profile.apply(args);
+        }
+    }
+
+    /// Options for a newly opened document in `language_id`.
+    /// Options given on the command line win over the language's profile.
+    pub fn options(&self, language_id: &str, args: &Args) -> Options {
+        let mut options = args.options;
+        for p in &self.profiles {
+            if p.language_id.as_deref() == Some(language_id) {
+                if let Some(split) = p.split.filter(|_| !args.given.split) {
+                    options.split = split;
+                }
+                if let Some(algorithm) = p.algorithm.filter(|_| !args.given.algorithm) {
+                    options.algorithm = algorithm;
+                }
+                if let Some(small_edit) = p.small_edit.filter(|_| !args.given.small_edit) {
+                    options.small_edit = small_edit;
+                }
+            }
+        }
+        options
+    }
+}
+
+impl Profile {
+    fn apply(&self, args: &mut Args) {
+        if let Some(split) = self.split {
+            args.options.split = split;
+        }
+        if let Some(algorithm) = self.algorithm {
+            args.options.algorithm = algorithm;
+        }
+        if let Some(small_edit) = self.small_edit {
+            args.options.small_edit = small_edit;
+        }
+        if let Some(percent) = self.min_free_memory {
+            args.min_free_memory = percent / 100.0;
+        }
+        if let Some(secs) = self.hang_timeout {
+            args.hang_timeout = Duration::from_secs(secs);
+        }
+        if let Some(max_crashes) = self.max_crashes {
+            args.max_crashes = max_crashes;
+        }
+        if let Some(secs) = self.crash_window {
+            args.crash_window = Duration::from_secs(secs);
+        }
+        if let Some(reissue) = self.reissue {
+            args.reissue = reissue;
+        }
+        if let Some(workers) = self.workers {
+            args.workers = workers;
+        }
+        args.env
+            .extend(self.env.iter().map(|(k, v)| (k.clone(), v.clone())));
+    }
+}
+
+#[test]
+fn profile_test() {
+    let config: Config = toml::from_str(
+        r#"
+        [[profile]]
+        max_crashes = 3
+        split = "off"
+
+        [[profile]]
+        server = "rust-analyzer"
+        env = { RA_LOG = "error" }
+        split = "all"
+
+        [[profile]]
+        language_id = "haskell"
+        split = "incremental"
+        "#,
+    )
+    .unwrap();
+
+    let mut args = Args::default();
+    config.apply(&["/usr/bin/rust-analyzer".to_owned()], &mut args);
+    assert_eq!(args.max_crashes, 3);
+    assert_eq!(args.options.split, Split::All);
+    assert_eq!(args.env["RA_LOG"], "error");
+    assert_eq!(config.options("haskell", &args).split, Split::Incremental);
+    assert_eq!(config.options("rust", &args).split, Split::All);
+
+    let cli = ["--split", "off", "hie"].iter().map(|s| s.to_string());
+    let args = Args::parse_onto(args, cli).unwrap();
+    assert_eq!(config.options("haskell", &args).split, Split::Off);
+}
@@ -0,0 +1,544 @@
+use std::cmp::Reverse;
+use std::collections::{hash_map, HashMap, HashSet};
+use std::env;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::marker::PhantomData;
+use std::mem;
+use std::path::PathBuf;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use lsp_types::*;
+use ropey::Rope;
+use serde::de::{self, Deserializer, Visitor};
+use serde::Deserialize;
+
+use lsp_diff::{Algorithm, LineHashes, Options, TextBuffer};
+
+use crate::notebook::NotebookDocument;
+
+/// The open documents and the memory they take up.
+/// Notebooks are kept as their cells' text documents plus the notebook itself.
+pub struct Documents<B = Rope> {
+    open: HashMap<Url, Document<B>>,
+    /// Open documents whose text was dropped because lsp-diff's copy went wrong.
+    /// Their changes are forwarded as is until the client opens them again.
+    dropped: HashSet<Url>,
+    /// Open documents whose text was moved to a temp file to save memory.
+    /// Their changes are forwarded as is too, but a restarted server is still sent them.
+    spilled: HashMap<Url, Spilled>,
+    /// Bytes held by the documents in `open`.
+    size: usize,
+    notebooks: HashMap<Url, NotebookDocument>,
+    /// The notebook of each open cell.
+    cells: HashMap<Url, Url>,
+    snapshots: Snapshots<B>,
+}
+
+impl<B: TextBuffer + Clone> Default for Documents<B> {
+    fn default() -> Self {
+        Documents::new()
+    }
+}
+
+impl<B: TextBuffer + Clone> Documents<B> {
+    pub fn new() -> Self {
+        Documents {
+            open: HashMap::with_capacity(20),
+            dropped: HashSet::new(),
+            spilled: HashMap::new(),
+            size: 0,
+            notebooks: HashMap::new(),
+            cells: HashMap::new(),
+            snapshots: Snapshots::default(),
+        }
+    }
+
+    pub fn open(&mut self, uri: Url, doc: Document<B>) {
+        self.dropped.remove(&uri);
+        self.unspill(&uri);
+        self.size += doc.size();
+        self.snapshots.set(&uri, &doc);
+        if let Some(old) = self.open.insert(uri, doc) {
+            self.size -= old.size();
+        }
+    }
+
+    pub fn close(&mut self, uri: &Url) {
+        self.dropped.remove(uri);
+        self.unspill(uri);
+        self.cells.remove(uri);
+        self.snapshots.remove(uri);
+        if let Some(old) = self.open.remove(uri) {
+            self.size -= old.size();
+        }
+    }
+
+    /// Forgets the text of `uri`, its changes are forwarded as is from now on.
+    pub fn drop_text(&mut self, uri: &Url) {
+        self.snapshots.remove(uri);
+        if let Some(old) = self.open.remove(uri) {
+            self.size -= old.size();
+            self.dropped.insert(uri.clone());
+        }
+    }
+
+    /// Moves the text of `uri` to a temp file, its changes are forwarded as is from now on.
+    /// If writing the file fails the text stays.
+    pub fn spill(&mut self, uri: &Url) -> io::Result<()> {
+        let doc = match self.open.get(uri) {
+            Some(doc) => doc,
+            None => return Ok(()),
+        };
+        let spilled = Spilled::new(doc)?;
+        self.spilled.insert(uri.clone(), spilled);
+        self.snapshots.remove(uri);
+        if let Some(old) = self.open.remove(uri) {
+            self.size -= old.size();
+        }
+        Ok(())
+    }
+
+    fn unspill(&mut self, uri: &Url) {
+        if let Some(spilled) = self.spilled.remove(uri) {
+            let _ = fs::remove_file(spilled.path);
+        }
+    }
+
+    /// Records changes to a spilled document, for replaying it later.
+    pub fn edit_spilled(
+        &mut self,
+        uri: &Url,
+        version: Option<u64>,
+        changes: &[TextDocumentContentChangeEvent],
+    ) {
+        let recorded = match self.spilled.get_mut(uri) {
+            Some(spilled) => spilled.record(version, changes),
+            None => return,
+        };
+        if let Err(e) = recorded {
+            log::warn!("Unable to update the spilled text of {}: {}", uri, e);
+            self.unspill(uri);
+            self.dropped.insert(uri.clone());
+        }
+    }
+
+    pub fn get(&self, uri: &Url) -> Option<&Document<B>> {
+        self.open.get(uri)
+    }
+
+    /// The document to send a restarted server, read back from disk if it was spilled.
+    /// `None` if its text is unknown.
+    pub fn item(&self, uri: &Url) -> Option<TextDocumentItem> {
+        if let Some(doc) = self.open.get(uri) {
+            return Some(doc.item(uri));
+        }
+        match self.spilled.get(uri)?.item::<B>(uri) {
+            Ok(item) => Some(item),
+            Err(e) => {
+                log::warn!("Unable to read the spilled text of {}: {}", uri, e);
+                None
+            }
+        }
+    }
+
+    /// The open text documents that aren't notebook cells, spilled ones included.
+    pub fn items<'a>(&'a self) -> impl Iterator<Item = TextDocumentItem> + 'a {
+        let cells = &self.cells;
+        self.open
+            .keys()
+            .chain(self.spilled.keys())
+            .filter(move |uri| !cells.contains_key(*uri))
+            .filter_map(move |uri| self.item(uri))
+    }
+
+    /// Runs `f` on the document at `uri`, keeping track of its size.
+    pub fn edit<R>(&mut self, uri: &Url, f: impl FnOnce(&mut Document<B>) -> R) -> Option<R> {
+        let doc = self.open.get_mut(uri)?;
+        let before = doc.size();
+        let r = f(doc);
+        self.size = self.size + doc.size() - before;
+        self.snapshots.set(uri, doc);
+        Some(r)
+    }
+
+    pub fn is_dropped(&self, uri: &Url) -> bool {
+        self.dropped.contains(uri) || self.spilled.contains_key(uri)
+    }
+
+    /// The open text documents that aren't notebook cells.
+    pub fn iter(&self) -> impl Iterator<Item = (&Url, &Document<B>)> {
+        let cells = &self.cells;
+        self.open
+            .iter()
+            .filter(move |(uri, _)| !cells.contains_key(*uri))
+    }
+
+    pub fn open_notebook(&mut self, notebook: NotebookDocument) {
+        self.notebooks.insert(notebook.uri.clone(), notebook);
+    }
+
+    pub fn open_cell(&mut self, notebook: &Url, uri: Url, doc: Document<B>) {
+        self.cells.insert(uri.clone(), notebook.clone());
+        self.open(uri, doc);
+    }
+
+    /// Closes the notebook and its cells.
+    pub fn close_notebook(&mut self, uri: &Url) {
+        if let Some(notebook) = self.notebooks.remove(uri) {
+            for cell in &notebook.cells {
+                self.close(&cell.document);
+            }
+        }
+    }
+
+    pub fn notebook_mut(&mut self, uri: &Url) -> Option<&mut NotebookDocument> {
+        self.notebooks.get_mut(uri)
+    }
+
+    pub fn notebooks(&self) -> hash_map::Values<'_, Url, NotebookDocument> {
+        self.notebooks.values()
+    }
+
+    /// Messages about a cell queue behind its notebook, whose changes carry the cell's.
+    pub fn queue<'a>(&'a self, uri: &'a Url) -> &'a Url {
+        self.cells.get(uri).unwrap_or(uri)
+    }
+
+    /// Kept up to date with the open documents from here on.
+    pub fn snapshots(&self) -> Snapshots<B> {
+        self.snapshots.clone()
+    }
+
+    /// Bytes held by the open documents' texts and line hashes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Spills the texts of documents of at least `large` bytes, largest first,
+    /// until at most `limit` bytes are held. Returns the documents spilled.
+    pub fn shed(&mut self, limit: usize, large: usize) -> Vec<Url> {
+        let mut candidates: Vec<_> = self
+            .open
+            .iter()
+            .map(|(uri, doc)| (doc.size(), uri))
+            .filter(|&(size, _)| size >= large)
+            .collect();
+        candidates.sort_by_key(|&(size, _)| Reverse(size));
+        let mut size = self.size;
+        let shed: Vec<Url> = candidates
+            .into_iter()
+            .take_while(|&(doc_size, _)| {
+                let over = size > limit;
+                size -= doc_size;
+                over
+            })
+            .map(|(_, uri)| uri.clone())
+            .collect();
+        shed.into_iter()
+            .filter(|uri| match self.spill(uri) {
+                Ok(()) => true,
+                Err(e) => {
+                    log::warn!("Unable to spill the text of {}: {}", uri, e);
+                    false
+                }
+            })
+            .collect()
+    }
+}
+
+/// A document whose text is in a temp file, plus the changes made to it since.
+struct Spilled {
+    path: PathBuf,
+    language_id: String,
+    version: u64,
+    /// Ranged changes to the text in `path`, a full document change is written out.
+    changes: Vec<TextDocumentContentChangeEvent>,
+}
+
+impl Spilled {
+    fn new<B: TextBuffer>(doc: &Document<B>) -> io::Result<Self> {
+        static SPILLED: AtomicUsize = AtomicUsize::new(0);
+        let path = env::temp_dir().join(format!(
+            "lsp-diff-{}-spilled-{}.txt",
+            process::id(),
+            SPILLED.fetch_add(1, Ordering::SeqCst)
+        ));
+        let mut file = BufWriter::new(File::create(&path)?);
+        for chunk in doc.buffer.chunks(0..doc.buffer.len_chars()) {
+            file.write_all(chunk.as_bytes())?;
+        }
+        file.flush()?;
+        Ok(Spilled {
+            path,
+            language_id: doc.language_id.clone(),
+            version: doc.version,
+            changes: Vec::new(),
+        })
+    }
+
+    fn record(
+        &mut self,
+        version: Option<u64>,
+        changes: &[TextDocumentContentChangeEvent],
+    ) -> io::Result<()> {
+        if let Some(version) = version {
+            self.version = version;
+        }
+        for change in changes {
+            if change.range.is_some() {
+                self.changes.push(change.clone());
+            } else {
+                fs::write(&self.path, &change.text)?;
+                self.changes.clear();
+            }
+        }
+        Ok(())
+    }
+
+    fn item<B: TextBuffer>(&self, uri: &Url) -> io::Result<TextDocumentItem> {
+        let mut buffer = B::from_text(&fs::read_to_string(&self.path)?);
+        for change in &self.changes {
+            lsp_diff::apply_change(&mut buffer, change);
+        }
+        Ok(TextDocumentItem {
+            uri: uri.clone(),
+            language_id: self.language_id.clone(),
+            version: self.version,
+            text: buffer.text().into_owned(),
+        })
+    }
+}
+
+/// The latest text of each open document, for threads other than the one keeping `Documents`.
+/// Taking one is cheap for a `Rope`, it shares its nodes.
+pub struct Snapshots<B = Rope>(Arc<Mutex<HashMap<Url, Snapshot<B>>>>);
+
+pub struct Snapshot<B = Rope> {
+    pub buffer: B,
+    pub version: u64,
+    pub algorithm: Algorithm,
+}
+
+impl<B> Clone for Snapshots<B> {
+    fn clone(&self) -> Self {
+        Snapshots(self.0.clone())
+    }
+}
+
+impl<B> Default for Snapshots<B> {
+    fn default() -> Self {
+        Snapshots(Arc::new(Mutex::new(HashMap::new())))
+    }
+}
+
+impl<B: TextBuffer + Clone> Snapshots<B> {
+    fn set(&self, uri: &Url, doc: &Document<B>) {
+        let snapshot = Snapshot {
+            buffer: doc.buffer.clone(),
+            version: doc.version,
+            algorithm: doc.options.algorithm,
+        };
+        self.0.lock().unwrap().insert(uri.clone(), snapshot);
+    }
+
+    fn remove(&self, uri: &Url) {
+        self.0.lock().unwrap().remove(uri);
+    }
+
+    /// The document at `uri` if it is at `version`.
+    pub fn get(&self, uri: &Url, version: u64) -> Option<Snapshot<B>> {
+        let snapshots = self.0.lock().unwrap();
+        let snapshot = snapshots.get(uri).filter(|s| s.version == version)?;
+        Some(Snapshot {
+            buffer: snapshot.buffer.clone(),
+            ..*snapshot
+        })
+    }
+}
+
+/// The proxy's copy of an open text document.
+/// Everything needed to replay `didOpen` to a restarted server is kept.
+#[derive(Clone)]
+pub struct Document<B = Rope> {
+    pub buffer: B,
+    /// Kept in step with `buffer` by `apply`.
+    pub hashes: LineHashes,
+    pub language_id: String,
+    pub version: u64,
+    /// How changes are split, picked by the document's language when it is opened.
+    pub options: Options,
+}
+
+impl<B: TextBuffer> Document<B> {
+    pub fn new(item: TextDocumentItem, options: Options) -> Self {
+        let buffer = B::from_text(&item.text);
+        Document {
+            hashes: LineHashes::new(&buffer),
+            buffer,
+            language_id: item.language_id,
+            version: item.version,
+            options,
+        }
+    }
+
+    pub fn open(item: OpenItem<B>, options: Options) -> Self {
+        let Text(buffer) = item.text;
+        Document {
+            hashes: LineHashes::new(&buffer),
+            buffer,
+            language_id: item.language_id,
+            version: item.version,
+            options,
+        }
+    }
+
+    /// Bytes held by the text and line hashes.
+    pub fn size(&self) -> usize {
+        self.buffer.len_bytes() + mem::size_of_val(self.hashes.as_slice())
+    }
+
+    pub fn apply(&mut self, change: &TextDocumentContentChangeEvent) {
+        lsp_diff::apply_change(&mut self.buffer, change);
+        self.hashes.update(&self.buffer, change);
+    }
+
+    /// Whether the text is `text`, compared chunk by chunk.
+    pub fn matches(&self, text: &str) -> bool {
+        if self.buffer.len_bytes() != text.len() {
+            return false;
+        }
+        let mut rest = text.as_bytes();
+        self.buffer.chunks(0..self.buffer.len_chars()).all(|chunk| {
+            let (head, tail) = rest.split_at(chunk.len());
+            rest = tail;
+            head == chunk.as_bytes()
+        })
+    }
+
+    /// Replaces the text, keeping the version and options.
+    pub fn set_text(&mut self, text: &str) {
+        self.buffer = B::from_text(text);
+        self.hashes = LineHashes::new(&self.buffer);
+    }
+
+    pub fn item(&self, uri: &Url) -> TextDocumentItem {
+        TextDocumentItem {
+            uri: uri.clone(),
+            language_id: self.language_id.clone(),
+            version: self.version,
+            text: self.buffer.text().into_owned(),
+        }
+    }
+
+    /// A change replacing the server's copy with the full text.
+    /// It keeps the version: the text is the client's at that version, and a higher one would
+    /// collide with the version of the client's next change.
+    pub fn resync(&self, uri: &Url) -> DidChangeTextDocumentParams {
+        DidChangeTextDocumentParams {
+            text_document: VersionedTextDocumentIdentifier {
+                uri: uri.clone(),
+                version: Some(self.version),
+            },
+            content_changes: vec![TextDocumentContentChangeEvent {
+                range: None,
+                range_length: None,
+                text: self.buffer.text().into_owned(),
+            }],
+        }
+    }
+}
+
+/// `didOpen` params whose text is read straight into a buffer.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase", bound(deserialize = "B: TextBuffer"))]
+pub struct OpenParams<B = Rope> {
+    pub text_document: OpenItem<B>,
+}
+
+/// `didSave` params, lsp-types' lack the text.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SaveParams {
+    pub text_document: TextDocumentIdentifier,
+    /// Sent if the server takes saves, lsp-diff asks the client for it.
+    pub text: Option<String>,
+}
+
+/// A `TextDocumentItem` with a buffer for its text.
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase", bound(deserialize = "B: TextBuffer"))]
+pub struct OpenItem<B = Rope> {
+    #[serde(with = "url_serde")]
+    pub uri: Url,
+    pub language_id: String,
+    pub version: u64,
+    pub text: Text<B>,
+}
+
+/// Deserializes a JSON string into a buffer without an intermediate `String`.
+/// serde_json hands over the unescaped text in its scratch space, or borrowed from the input.
+#[derive(Debug)]
+pub struct Text<B>(pub B);
+
+impl<'de, B: TextBuffer> Deserialize<'de> for Text<B> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct TextVisitor<B>(PhantomData<B>);
+
+        impl<'de, B: TextBuffer> Visitor<'de> for TextVisitor<B> {
+            type Value = Text<B>;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a string")
+            }
+
+            fn visit_str<E: de::Error>(self, text: &str) -> Result<Self::Value, E> {
+                Ok(Text(B::from_text(text)))
+            }
+        }
+
+        deserializer.deserialize_str(TextVisitor(PhantomData))
+    }
+}
+
+#[test]
+fn shed_test() {
+    let uri = |name: &str| Url::parse(&format!("file:///{}", name)).unwrap();
+    let doc = |text: &str| {
+        let item: OpenItem<String> = serde_json::from_value(serde_json::json!({
+            "uri": "file:///x", "languageId": "rust", "version": 1, "text": text
+        }))
+        .unwrap();
+        Document::open(item, Options::default())
+    };
+    let mut docs = Documents::new();
+    docs.open(uri("big"), doc(&"x".repeat(1000)));
+    docs.open(uri("bigger"), doc(&"x".repeat(2000)));
+    docs.open(uri("small"), doc("x"));
+    let held = docs.size();
+
+    assert_eq!(docs.shed(held - 1500, 100), vec![uri("bigger")]);
+    assert!(docs.is_dropped(&uri("bigger")));
+    assert!(docs.size() < held - 1500);
+    assert!(docs.shed(0, 100).contains(&uri("big")));
+    assert!(docs.get(&uri("small")).is_some());
+
+    // Spilled documents can still be replayed, changes included.
+    let change = TextDocumentContentChangeEvent {
+        range: Some(Range::new(Position::new(0, 0), Position::new(0, 1))),
+        range_length: None,
+        text: "y".to_owned(),
+    };
+    docs.edit_spilled(&uri("big"), Some(2), &[change]);
+    let item = docs.item(&uri("big")).unwrap();
+    assert_eq!((item.version, &item.text[..2]), (2, "yx"));
+    assert_eq!(docs.items().count(), 3);
+
+    docs.open(uri("big"), doc("x"));
+    assert!(!docs.is_dropped(&uri("big")));
+    docs.close(&uri("bigger"));
+    assert_eq!(docs.items().count(), 2);
+}
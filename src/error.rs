@@ -0,0 +1,89 @@
+use std::fmt;
+use std::io;
+
+use lsp_types::{Range, Url};
+
+/// Everything that can go wrong while proxying a message.
+/// Each kind has a `Policy` saying how the proxy carries on.
+#[derive(Debug)]
+pub enum Error {
+    /// Reading from the client failed.
+    Client(io::Error),
+    /// A message whose headers lack a usable `Content-Length`.
+    Header(String),
+    /// Writing to the server failed, it most likely died.
+    Server(io::Error),
+    /// A change to a document that was never opened, or no longer tracked.
+    Unopened(Url),
+    /// A change to a document whose text lsp-diff no longer holds.
+    Dropped(Url),
+    /// A change whose range lies outside lsp-diff's copy of the document.
+    BadRange(Url, Range),
+    /// lsp-diff's copy of a document differs from the text the client saved.
+    Unsaved(Url),
+    Json(serde_json::Error),
+}
+
+/// How the proxy recovers from an `Error`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    /// Send the client's message to the server untouched.
+    Forward,
+    /// Replace the server's copy of the document with lsp-diff's.
+    Resync,
+    /// Treat the server as crashed.
+    Restart,
+    /// Drop the message and tell the user through `window/showMessage`.
+    Report,
+    /// Nothing sensible is left to do.
+    Exit,
+}
+
+impl Error {
+    pub fn policy(&self) -> Policy {
+        match *self {
+            Error::Client(_) => Policy::Exit,
+            Error::Header(_) => Policy::Report,
+            Error::Server(_) => Policy::Restart,
+            Error::Unopened(_) | Error::Dropped(_) | Error::BadRange(..) | Error::Json(_) => {
+                Policy::Forward
+            }
+            Error::Unsaved(_) => Policy::Resync,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Client(ref e) => write!(f, "client connection failed: {}", e),
+            Error::Header(ref h) => write!(f, "malformed message header '{}'", h.trim_end()),
+            Error::Server(ref e) => write!(f, "writing to the server failed: {}", e),
+            Error::Unopened(ref uri) => write!(f, "change to unopened document {}", uri),
+            Error::Dropped(ref uri) => {
+                write!(
+                    f,
+                    "lsp-diff doesn't hold {}, changes are forwarded as is",
+                    uri
+                )
+            }
+            Error::BadRange(ref uri, range) => write!(
+                f,
+                "change range {}:{}..{}:{} is outside of {}",
+                range.start.line, range.start.character, range.end.line, range.end.character, uri
+            ),
+            Error::Unsaved(ref uri) => {
+                write!(f, "lsp-diff's copy of {} differs from the saved text", uri)
+            }
+            Error::Json(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Json(e)
+    }
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
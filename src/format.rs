@@ -0,0 +1,72 @@
+//! Formatters tend to answer with one edit replacing the whole document,
+//! which costs the editor its cursor position, folds and marks.
+//! lsp-diff diffs such responses against its copy of the document into minimal edits.
+
+use std::panic::{self, AssertUnwindSafe};
+
+use lsp_diff::{Algorithm, LineHashes, TextBuffer};
+use lsp_types::TextEdit;
+use serde_json::Value;
+
+use crate::document::Document;
+use crate::stats;
+
+/// Requests answered with `TextEdit`s for the request's document.
+pub const METHODS: &[&str] = &["textDocument/formatting", "textDocument/rangeFormatting"];
+
+/// Rewrites the edits of a response to one of `METHODS` into minimal ones.
+/// `doc` is the document as it was when the request was sent.
+pub fn minimize(resp: &[u8], doc: &Document) -> Option<Vec<u8>> {
+    let mut resp: Value = serde_json::from_slice(resp).ok()?;
+    let edits: Vec<TextEdit> = serde_json::from_value(resp.get("result")?.clone()).ok()?;
+    if !edits
+        .iter()
+        .all(|edit| lsp_diff::contains(&doc.buffer, edit.range))
+    {
+        return None;
+    }
+    let edits = minimize_edits(&doc.buffer, &doc.hashes, &edits, doc.options.algorithm)?;
+    resp["result"] = serde_json::to_value(edits).ok()?;
+    serde_json::to_vec(&resp).ok()
+}
+
+/// `lsp_diff::minimize_edits`, checked to have the same effect on `buffer` as `edits`.
+/// `None` if it doesn't or panics, the original edits are then sent as they are.
+pub fn minimize_edits<B: TextBuffer>(
+    buffer: &B,
+    hashes: &LineHashes,
+    edits: &[TextEdit],
+    algorithm: Algorithm,
+) -> Option<Vec<TextEdit>> {
+    let checked = || {
+        let minimized = lsp_diff::minimize_edits(buffer, hashes, edits, algorithm);
+        if lsp_diff::apply_edits(buffer, &minimized) == lsp_diff::apply_edits(buffer, edits) {
+            Some(minimized)
+        } else {
+            None
+        }
+    };
+    let minimized = panic::catch_unwind(AssertUnwindSafe(checked)).unwrap_or(None);
+    if minimized.is_none() {
+        log::warn!("minimized edits diverged, forwarding {:?}", edits);
+        stats::record(|s| s.fallbacks += 1);
+    }
+    minimized
+}
+
+#[test]
+fn minimize_crlf_test() {
+    use lsp_types::{Position, Range};
+
+    let buffer = ropey::Rope::from_str("fn main(){\r\n}\r\n");
+    let edits = [TextEdit {
+        range: Range::new(Position::new(0, 0), Position::new(2, 0)),
+        new_text: "fn main() {\n}\n".to_owned(),
+    }];
+    let hashes = LineHashes::new(&buffer);
+    let minimized = minimize_edits(&buffer, &hashes, &edits, Algorithm::Myers).unwrap();
+    assert_eq!(
+        lsp_diff::apply_edits(&buffer, &minimized),
+        "fn main() {\n}\n"
+    );
+}
@@ -0,0 +1,331 @@
+//! Splits LSP content changes into smaller, finer grained changes.
+//!
+//! `Incremental` turns a ranged change into the minimal edits within its range,
+//! `Full` turns a full document change into line edits.
+//! `split_change` picks between them the way the `lsp-diff` proxy does.
+//! `minimize_edits` uses both to shrink `TextEdit`s, like a formatter's.
+//! Both work on any `TextBuffer`, ropey's `Rope` and `String` are provided.
+
+pub mod buffer;
+pub mod chars_diff;
+pub mod rope_diff;
+
+pub use buffer::TextBuffer;
+pub use chars_diff::Changes;
+pub use rope_diff::{Algorithm, LineHashes};
+/// The rope the proxy keeps documents in.
+pub use ropey;
+
+use std::str::FromStr;
+
+use lsp_types::{Range, TextDocumentContentChangeEvent, TextEdit};
+use serde::Deserialize;
+use smallvec::smallvec;
+
+use chars_diff::Incremental;
+use rope_diff::Full;
+
+/// Which content changes get split into finer changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Split {
+    All,
+    /// Only changes with a range.
+    Incremental,
+    /// Only full document changes.
+    Full,
+    Off,
+}
+
+impl Split {
+    pub fn incremental(self) -> bool {
+        self == Split::All || self == Split::Incremental
+    }
+
+    pub fn full(self) -> bool {
+        self == Split::All || self == Split::Full
+    }
+}
+
+impl FromStr for Split {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "all" => Ok(Split::All),
+            "incremental" => Ok(Split::Incremental),
+            "full" => Ok(Split::Full),
+            "off" => Ok(Split::Off),
+            _ => Err(format!("unknown split mode '{}'", s)),
+        }
+    }
+}
+
+/// How changes are split.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Options {
+    pub split: Split,
+    pub algorithm: Algorithm,
+    /// Single line edits shorter than this many characters are kept as is.
+    pub small_edit: u64,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            split: Split::All,
+            algorithm: Algorithm::Myers,
+            small_edit: 3,
+        }
+    }
+}
+
+impl Options {
+    /// Whether `split_change` splits `change` rather than returning it as is.
+    /// A reversed range is returned as is.
+    pub fn splits(&self, change: &TextDocumentContentChangeEvent) -> bool {
+        match change.range {
+            None => self.split.full(),
+            Some(Range { start, end }) => {
+                self.split.incremental()
+                    && start <= end
+                    && !(start.line == end.line
+                        && end.character - start.character < self.small_edit)
+            }
+        }
+    }
+}
+
+/// Changes with the same effect on `buffer` as `change`, finer grained where `options` allow.
+/// A ranged `change` must lie within `buffer`, see `contains`.
+pub fn split_change<B: TextBuffer>(
+    buffer: &B,
+    change: &TextDocumentContentChangeEvent,
+    options: Options,
+) -> Changes {
+    if !options.splits(change) {
+        return smallvec![change.clone()];
+    }
+    match change.range {
+        None => diff_documents(buffer, &change.text, options),
+        Some(range) => Incremental::diff(buffer, range, &change.text),
+    }
+}
+
+/// Like `split_change`, with `hashes` of `buffer`'s lines kept from earlier changes.
+pub fn split_change_hashed<B: TextBuffer>(
+    buffer: &B,
+    hashes: &LineHashes,
+    change: &TextDocumentContentChangeEvent,
+    options: Options,
+) -> Changes {
+    if !options.splits(change) {
+        return smallvec![change.clone()];
+    }
+    match change.range {
+        None => Full::diff(buffer, hashes, &change.text, options.algorithm),
+        Some(range) => Incremental::diff(buffer, range, &change.text),
+    }
+}
+
+/// Line changes that turn `old` into `new`, matched up with `options.algorithm`.
+pub fn diff_documents<B: TextBuffer>(old: &B, new: &str, options: Options) -> Changes {
+    Full::diff(old, &LineHashes::new(old), new, options.algorithm)
+}
+
+/// Minimal edits with the same effect on `buffer` as `edits`, e.g. a formatter's whole document
+/// replacement. All of them are positioned in `buffer`, like `edits`. `hashes` must be `buffer`'s.
+/// Lines are matched up with `algorithm`, then each changed block is diffed by character.
+pub fn minimize_edits<B: TextBuffer>(
+    buffer: &B,
+    hashes: &LineHashes,
+    edits: &[TextEdit],
+    algorithm: Algorithm,
+) -> Vec<TextEdit> {
+    let new = apply_edits(buffer, edits);
+    Full::edits(buffer, hashes, &new, algorithm)
+        .iter()
+        .flat_map(|block| match block.range {
+            Some(range) => Incremental::edits(buffer, range, &block.text),
+            None => smallvec![block.clone()],
+        })
+        .filter_map(|change| {
+            Some(TextEdit {
+                range: change.range?,
+                new_text: change.text,
+            })
+        })
+        .collect()
+}
+
+/// The text of `buffer` with `edits` applied. They must lie within `buffer` and not overlap.
+pub fn apply_edits<B: TextBuffer>(buffer: &B, edits: &[TextEdit]) -> String {
+    let mut edits: Vec<_> = edits.iter().collect();
+    edits.sort_by_key(|edit| (edit.range.start.line, edit.range.start.character));
+    let mut text = String::with_capacity(buffer.len_bytes());
+    let mut at = 0;
+    for edit in edits {
+        let start = buffer.position_to_char(edit.range.start).max(at);
+        let end = buffer.position_to_char(edit.range.end).max(start);
+        text.push_str(&buffer.slice(at..start));
+        text.push_str(&edit.new_text);
+        at = end;
+    }
+    text.push_str(&buffer.slice(at..buffer.len_chars()));
+    text
+}
+
+/// Applies `change` to `buffer`.
+pub fn apply_change<B: TextBuffer>(buffer: &mut B, change: &TextDocumentContentChangeEvent) {
+    let range = match change.range {
+        Some(range) => range,
+        None => {
+            *buffer = B::from_text(&change.text);
+            return;
+        }
+    };
+    let start = buffer.position_to_char(range.start);
+    let end = buffer.position_to_char(range.end);
+    if start < end {
+        buffer.remove(start..end);
+    }
+    if !change.text.is_empty() {
+        buffer.insert(start, &change.text);
+    };
+    log::trace!(
+        "applied {:?} {:?}, line {} is now {:?}",
+        range,
+        change.text,
+        range.start.line,
+        buffer.line(range.start.line as usize)
+    );
+}
+
+/// Whether `range` lies within `buffer`.
+/// Columns past the end of their line are fine, they mean the end of the line.
+pub fn contains<B: TextBuffer>(buffer: &B, range: Range) -> bool {
+    range.start <= range.end && (range.end.line as usize) < buffer.len_lines()
+}
+
+#[test]
+fn splits_test() {
+    let change = |start, end| TextDocumentContentChangeEvent {
+        range: Some(Range::new(
+            lsp_types::Position::new(0, start),
+            lsp_types::Position::new(0, end),
+        )),
+        range_length: None,
+        text: String::new(),
+    };
+    let options = Options::default();
+    assert!(options.splits(&change(0, 10)));
+    assert!(!options.splits(&change(0, 1)));
+    assert!(!options.splits(&change(10, 0)));
+}
+
+#[test]
+fn minimize_edits_test() {
+    use lsp_types::Position;
+
+    let old = String::from("fn main(){\nlet x=1;\n}\n");
+    let new = "fn main() {\n    let x = 1;\n}\n";
+    let whole = TextEdit {
+        range: Range::new(Position::new(0, 0), Position::new(3, 0)),
+        new_text: new.to_owned(),
+    };
+    let edits = minimize_edits(&old, &LineHashes::new(&old), &[whole], Algorithm::Myers);
+    assert_eq!(apply_edits(&old, &edits), new);
+    assert!(edits.iter().all(|e| e.new_text.trim().is_empty()));
+    assert_eq!(edits[0].range.start, Position::new(0, 9));
+
+    // "€" and "キ" share their middle byte, the edit still replaces the whole char.
+    let old = String::from("a€b\n");
+    let edit = TextEdit {
+        range: Range::new(Position::new(0, 0), Position::new(1, 0)),
+        new_text: "aキb\n".to_owned(),
+    };
+    let edits = minimize_edits(&old, &LineHashes::new(&old), &[edit], Algorithm::Myers);
+    assert_eq!(apply_edits(&old, &edits), "aキb\n");
+    assert_eq!(edits.len(), 1);
+    assert_eq!(
+        edits[0].range,
+        Range::new(Position::new(0, 1), Position::new(0, 2))
+    );
+    // Line breaks that change from `\r\n` to `\n` are replaced whole.
+    let old = String::from("fn main(){\r\n}\r\n");
+    let edit = TextEdit {
+        range: Range::new(Position::new(0, 0), Position::new(2, 0)),
+        new_text: "fn main() {\n}\n".to_owned(),
+    };
+    let edits = minimize_edits(&old, &LineHashes::new(&old), &[edit], Algorithm::Myers);
+    assert_eq!(apply_edits(&old, &edits), "fn main() {\n}\n");
+}
+
+#[test]
+fn split_change_test() {
+    use lsp_types::Position;
+    use ropey::Rope;
+
+    fn check<B: TextBuffer>(old: &str, change: &TextDocumentContentChangeEvent, expected: &str) {
+        let mut buffer = B::from_text(old);
+        for c in &split_change(&buffer.clone(), change, Options::default()) {
+            assert!(c.range.is_some(), "{:?} to {:?}", old, expected);
+            apply_change(&mut buffer, c);
+        }
+        assert_eq!(buffer.text(), expected, "{:?} by {:?}", old, change);
+    }
+
+    // Every line break, and columns after chars that take one, two or four UTF-8 bytes and one
+    // or two UTF-16 units.
+    let cases = [
+        ("a\nb\nc\n", "a\nx\nc\n"),
+        ("a\r\nb\r\nc\r\n", "a\r\nbb\r\nc\n"),
+        ("a\rb\rc", "a\rx\rc\r"),
+        ("a\r\nb", "a\rb"),
+        ("a\rb", "a\r\nb"),
+        ("a\nb", "a\r\nb\r"),
+        ("é😀x\n😀y\n", "é😀z\n😀😀y\n"),
+        ("😀\r\n€\r\n", "😀€\r\n€\r\nキ"),
+    ];
+    for &(old, new) in cases.iter() {
+        let full = TextDocumentContentChangeEvent {
+            range: None,
+            range_length: None,
+            text: new.to_owned(),
+        };
+        check::<Rope>(old, &full, new);
+        check::<String>(old, &full, new);
+        let ranged = TextDocumentContentChangeEvent {
+            range: Some(Range::new(
+                Position::new(0, 0),
+                String::from(old).char_to_position(old.chars().count()),
+            )),
+            ..full
+        };
+        check::<Rope>(old, &ranged, new);
+        check::<String>(old, &ranged, new);
+    }
+
+    // Ranges starting and ending after a surrogate pair and around line breaks.
+    let ranged = [
+        ("x😀y\r\nz", (0, 3), (1, 0), "w\r\n", "x😀w\r\nz"),
+        ("x😀y\r\nz", (0, 0), (0, 3), "ab😀", "ab😀y\r\nz"),
+        ("a\rbc\rd", (1, 0), (2, 1), "x\r\ny", "a\rx\r\ny"),
+        ("a\nbc\r\nd\n", (0, 1), (2, 0), "\r\nbd\n", "a\r\nbd\nd\n"),
+    ];
+    for &(old, (l0, c0), (l1, c1), text, expected) in ranged.iter() {
+        let change = TextDocumentContentChangeEvent {
+            range: Some(Range::new(Position::new(l0, c0), Position::new(l1, c1))),
+            range_length: None,
+            text: text.to_owned(),
+        };
+        check::<Rope>(old, &change, expected);
+        check::<String>(old, &change, expected);
+    }
+
+    let old = Rope::from_str("a\r\nb\rc\n");
+    let mut buffer = old.clone();
+    for c in &diff_documents(&old, "a\nb\r\nc\r", Options::default()) {
+        apply_change(&mut buffer, c);
+    }
+    assert_eq!(buffer.to_string(), "a\nb\r\nc\r");
+}
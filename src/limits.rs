@@ -0,0 +1,204 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::atomic::{AtomicI32, Ordering};
+
+/// Limits and isolation applied to the spawned server.
+#[derive(Debug, Clone)]
+pub struct Limits {
+    /// `RLIMIT_AS` in bytes.
+    pub address_space: Option<u64>,
+    /// `RLIMIT_CPU` in seconds.
+    pub cpu_time: Option<u64>,
+    /// `RLIMIT_NOFILE`.
+    pub open_files: Option<u64>,
+    /// Run the server in its own process group, so signals reach the processes it spawns too.
+    pub process_group: bool,
+    /// A cgroup v2 directory delegated to the user, servers join it before they exec.
+    pub cgroup: Option<PathBuf>,
+    /// `memory.max` of `cgroup` in bytes.
+    pub cgroup_memory: Option<u64>,
+    /// `cpu.max` of `cgroup` in CPUs.
+    pub cgroup_cpus: Option<f64>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            address_space: None,
+            cpu_time: None,
+            open_files: None,
+            process_group: true,
+            cgroup: None,
+            cgroup_memory: None,
+            cgroup_cpus: None,
+        }
+    }
+}
+
+/// Process group of the current server, 0 if it has none.
+/// Read by the signal handler, so it has to be a static.
+static SERVER_PGID: AtomicI32 = AtomicI32::new(0);
+
+impl Limits {
+    /// Makes the child apply the rlimits, process group and cgroup before it execs the server,
+    /// so it never runs uncapped.
+    /// Errs if the cgroup couldn't be set up, the server then runs outside of it.
+    #[cfg(unix)]
+    pub fn apply(&self, cmd: &mut Command) -> io::Result<()> {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::process::CommandExt;
+
+        macro_rules! set_limit {
+            ($resource:expr, $limit:expr) => {
+                if let Some(limit) = $limit {
+                    let rlim = libc::rlimit {
+                        rlim_cur: limit as libc::rlim_t,
+                        rlim_max: limit as libc::rlim_t,
+                    };
+                    if libc::setrlimit($resource as _, &rlim) != 0 {
+                        return Err(io::Error::last_os_error());
+                    }
+                }
+            };
+        }
+
+        let Limits {
+            address_space,
+            cpu_time,
+            open_files,
+            process_group,
+            ..
+        } = *self;
+        let (procs, cgroup_error) = match self.prepare_cgroup() {
+            Ok(procs) => (procs, Ok(())),
+            Err(e) => (None, Err(e)),
+        };
+        let procs = procs.map(|procs| CString::new(procs.as_os_str().as_bytes()).unwrap());
+        // Only async-signal-safe calls are allowed between fork and exec.
+        unsafe {
+            cmd.pre_exec(move || {
+                if process_group && libc::setpgid(0, 0) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                if let Some(ref procs) = procs {
+                    join_cgroup(procs)?;
+                }
+                set_limit!(libc::RLIMIT_AS, address_space);
+                set_limit!(libc::RLIMIT_CPU, cpu_time);
+                set_limit!(libc::RLIMIT_NOFILE, open_files);
+                Ok(())
+            });
+        }
+        cgroup_error
+    }
+
+    #[cfg(not(unix))]
+    pub fn apply(&self, _cmd: &mut Command) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Called with the pid of a freshly spawned server.
+    pub fn spawned(&self, pid: u32) {
+        if self.process_group {
+            SERVER_PGID.store(pid as i32, Ordering::SeqCst);
+        }
+    }
+
+    /// Called once the server is reaped, its pid may belong to another process from now on.
+    pub fn reaped(&self) {
+        SERVER_PGID.store(0, Ordering::SeqCst);
+    }
+
+    /// Creates `cgroup` and sets its limits. Returns its `cgroup.procs`, for the server to join.
+    fn prepare_cgroup(&self) -> io::Result<Option<PathBuf>> {
+        let cgroup = match self.cgroup {
+            Some(ref cgroup) => cgroup,
+            None => return Ok(None),
+        };
+        fs::create_dir_all(cgroup)?;
+        if let Some(memory) = self.cgroup_memory {
+            fs::write(cgroup.join("memory.max"), memory.to_string())?;
+        }
+        if let Some(cpus) = self.cgroup_cpus {
+            let period = 100_000;
+            let quota = (cpus * f64::from(period)) as u64;
+            fs::write(cgroup.join("cpu.max"), format!("{} {}", quota, period))?;
+        }
+        Ok(Some(cgroup.join("cgroup.procs")))
+    }
+
+    /// Sends `signal` to the server, and to its whole process group if it has one.
+    #[cfg(unix)]
+    pub fn signal(&self, pid: u32, signal: i32) {
+        let target = if self.process_group {
+            -(pid as i32)
+        } else {
+            pid as i32
+        };
+        unsafe {
+            libc::kill(target, signal);
+        }
+    }
+}
+
+/// Writes the calling process' pid to `procs`, a `cgroup.procs` file.
+/// Runs between fork and exec, so it formats the pid without allocating.
+#[cfg(unix)]
+fn join_cgroup(procs: &std::ffi::CStr) -> io::Result<()> {
+    let mut digits = [0u8; 20];
+    let mut start = digits.len();
+    let mut pid = unsafe { libc::getpid() } as u32;
+    loop {
+        start -= 1;
+        digits[start] = b'0' + (pid % 10) as u8;
+        pid /= 10;
+        if pid == 0 {
+            break;
+        }
+    }
+    unsafe {
+        let fd = libc::open(procs.as_ptr(), libc::O_WRONLY);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let len = digits.len() - start;
+        let written = libc::write(fd, digits[start..].as_ptr() as *const libc::c_void, len);
+        let error = io::Error::last_os_error();
+        libc::close(fd);
+        if written != len as isize {
+            return Err(error);
+        }
+    }
+    Ok(())
+}
+
+/// Passes SIGTERM, SIGINT and SIGHUP sent to lsp-diff on to the server's process group,
+/// then dies of the signal as usual.
+#[cfg(unix)]
+pub fn forward_signals() {
+    extern "C" fn forward(signal: libc::c_int) {
+        let pgid = SERVER_PGID.load(Ordering::SeqCst);
+        unsafe {
+            if pgid > 0 {
+                libc::kill(-pgid, signal);
+            }
+            libc::signal(signal, libc::SIG_DFL);
+            libc::raise(signal);
+        }
+    }
+
+    for &signal in &[libc::SIGTERM, libc::SIGINT, libc::SIGHUP] {
+        unsafe {
+            libc::signal(
+                signal,
+                forward as extern "C" fn(libc::c_int) as libc::sighandler_t,
+            );
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub fn forward_signals() {}
@@ -0,0 +1,147 @@
+use std::cmp::{self, Reverse};
+use std::fs::OpenOptions;
+use std::io::{self, Write};
+use std::path::Path;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use log::{LevelFilter, Log, Metadata, Record};
+use serde_json::json;
+
+/// Which records are logged, written as `warn,lsp_diff::chars_diff=trace`:
+/// a default level followed by levels for targets, usually module paths.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Filter {
+    pub default: LevelFilter,
+    /// Longest first, the first target that is a prefix of the record's target wins.
+    targets: Vec<(String, LevelFilter)>,
+}
+
+impl Filter {
+    fn level(&self, target: &str) -> LevelFilter {
+        self.targets
+            .iter()
+            .find(|(t, _)| target.starts_with(t.as_str()))
+            .map_or(self.default, |&(_, level)| level)
+    }
+
+    fn max(&self) -> LevelFilter {
+        self.targets
+            .iter()
+            .map(|&(_, level)| level)
+            .fold(self.default, cmp::max)
+    }
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Filter {
+            default: LevelFilter::Warn,
+            targets: Vec::new(),
+        }
+    }
+}
+
+impl FromStr for Filter {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        let level = |l: &str| {
+            l.parse::<LevelFilter>()
+                .map_err(|_| format!("unknown log level '{}'", l))
+        };
+
+        let mut filter = Filter::default();
+        for part in s.split(',').filter(|p| !p.is_empty()) {
+            match part.find('=') {
+                Some(i) => filter
+                    .targets
+                    .push((part[..i].to_owned(), level(&part[i + 1..])?)),
+                None => filter.default = level(part)?,
+            }
+        }
+        // Longer targets are more specific.
+        filter.targets.sort_by_key(|t| Reverse(t.0.len()));
+        Ok(filter)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    /// `[LEVEL target] message`
+    Text,
+    /// One object per line with `time` (seconds since the epoch), `level`, `target` and `message`.
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            _ => Err(format!("unknown log format '{}'", s)),
+        }
+    }
+}
+
+/// Logs to stderr, which the client usually shows or saves, or to a file.
+struct Logger {
+    filter: Filter,
+    format: Format,
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.filter.level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let line = match self.format {
+            Format::Text => format!("[{} {}] {}", record.level(), record.target(), record.args()),
+            Format::Json => {
+                let time = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0.0, |t| {
+                        t.as_secs() as f64 + f64::from(t.subsec_micros()) / 1e6
+                    });
+                json!({
+                    "time": time,
+                    "level": record.level().to_string(),
+                    "target": record.target(),
+                    "message": record.args().to_string(),
+                })
+                .to_string()
+            }
+        };
+        let mut out = self.out.lock().unwrap();
+        let _ = writeln!(out, "{}", line);
+    }
+
+    fn flush(&self) {
+        let _ = self.out.lock().unwrap().flush();
+    }
+}
+
+/// Installs the logger, appending to `file` if given instead of writing to stderr.
+pub fn init(filter: Filter, format: Format, file: Option<&Path>) -> io::Result<()> {
+    let out: Box<dyn Write + Send> = match file {
+        Some(path) => Box::new(io::LineWriter::new(
+            OpenOptions::new().create(true).append(true).open(path)?,
+        )),
+        None => Box::new(io::stderr()),
+    };
+    log::set_max_level(filter.max());
+    let logger = Logger {
+        filter,
+        format,
+        out: Mutex::new(out),
+    };
+    log::set_logger(Box::leak(Box::new(logger))).expect("logger already set");
+    Ok(())
+}
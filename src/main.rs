@@ -1,336 +1,1001 @@
-mod chars_diff;
-use chars_diff::{Changes, Incremental, RopeSlice};
-mod rope_diff;
-use rope_diff::Full;
+mod cli;
+use cli::{Args, Transport};
+mod commands;
+use commands::Command;
+mod config;
+use config::Config;
+mod document;
+use document::{Document, Documents, OpenParams, SaveParams};
+mod error;
+use error::{Error, Policy};
+mod format;
+mod limits;
+mod logger;
+mod notebook;
+use notebook::{
+    DidChangeNotebookDocumentParams, DidCloseNotebookDocumentParams, DidOpenNotebookDocumentParams,
+    NotebookDid, NOTEBOOK_METHODS,
+};
+mod pending;
+mod rpc;
+use rpc::{Envelope, ErrResp, Noti, NotiS, Params, Resp, Ticking};
+mod server;
+mod stats;
+use server::{Input, Server, Supervisor};
+mod stderr_log;
+mod tokens;
+mod workers;
+mod workspace_edit;
+use workers::Workers;
 
-use std::collections::HashMap;
 use std::env;
-use std::io::{stdin, BufRead, Read, Stdin, StdinLock, Write};
-use std::process::{ChildStdin, Command, Stdio};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::mem;
+use std::net::TcpListener;
+use std::panic::{self, AssertUnwindSafe};
+use std::process;
 use std::str;
-use std::thread;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time::{Duration, Instant};
 
-use locked_resource::*;
+use lsp_diff::{Changes, Options, Split};
 use lsp_types::*;
 use ropey::Rope;
-use serde::{de::IgnoredAny, Deserialize, Serialize};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
 use smallvec::smallvec;
 
 fn main() {
-    let server = env::args()
-        .nth(1)
-        .expect("Provide lsp command as first argument.");
-
-    let server = Command::new(&server)
-        .args(env::args().skip(2))
-        .stdin(Stdio::piped())
-        .spawn()
-        .unwrap_or_else(|_| panic!("Unable to start server with command: '{}'", server));
-    let mut server_stdin = server.stdin.expect("server stdin failed");
+    let cli: Vec<String> = env::args().skip(1).collect();
+    let args = Args::parse(cli.iter().cloned()).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        process::exit(if e == cli::USAGE { 0 } else { 2 })
+    });
+    logger::init(
+        args.log_level.clone(),
+        args.log_format,
+        args.log_file.as_deref(),
+    )
+    .unwrap_or_else(|e| {
+        eprintln!("Unable to open log file: {}", e);
+        process::exit(2)
+    });
+    limits::forward_signals();
+    if let Some(ref trace) = args.trace {
+        match File::create(trace) {
+            Ok(file) => rpc::set_trace(file),
+            Err(e) => log::error!("Unable to create trace file {}: {}", trace.display(), e),
+        }
+    }
 
-    let mut url_text: HashMap<Url, Rope> = HashMap::with_capacity(20);
+    let mut url_text: Documents = Documents::new();
 
-    let open = |DidOpenTextDocumentParams { text_document }, url_text: &mut HashMap<Url, Rope>| {
-        url_text.insert(text_document.uri, Rope::from(text_document.text));
+    let open = |OpenParams { text_document }: OpenParams, url_text: &mut Documents, options| {
+        url_text.open(
+            text_document.uri.clone(),
+            Document::open(text_document, options),
+        );
     };
 
-    let close = |DidCloseTextDocumentParams { text_document },
-                 url_text: &mut HashMap<Url, Rope>| {
-        url_text.remove(&text_document.uri);
+    let close = |DidCloseTextDocumentParams { text_document }, url_text: &mut Documents| {
+        url_text.close(&text_document.uri);
     };
 
+    // Changes are applied to `url_text` right away, split on a worker and sent from there.
     let change = |DidChangeTextDocumentParams {
                       text_document,
                       content_changes,
                   },
-                  server_stdin: &mut ChildStdin,
-                  url_text: &mut HashMap<Url, Rope>| {
-        let rope = url_text.get_mut(&text_document.uri).unwrap_or_else(|| {
-            panic!(
-                "Error: Change to unopened text_document\n {}",
-                text_document.uri
-            )
+                  server: &mut Server,
+                  url_text: &mut Documents,
+                  splitting: bool,
+                  workers: &Workers| {
+        let uri = text_document.uri.clone();
+        let version = text_document.version;
+        if url_text.is_dropped(&uri) {
+            url_text.edit_spilled(&uri, version, &content_changes);
+            return Err(Error::Dropped(uri));
+        }
+        let applied = url_text.edit(&uri, |doc| {
+            prepare(doc, version, &content_changes, splitting)
         });
-        let content_changes = content_changes
-            .into_iter()
-            .flat_map(|change| match change.range {
-                None => {
-                    // Slice is for future compatibility
-                    let ch = Full::diff(rope.slice(..), change.text.as_str());
-                    for c in &ch {
-                        // TODO Handle Unicode consistently.
-                        let Range { start, end } = c.range.unwrap();
-                        let start_offset =
-                            rope.line_to_char(start.line as usize) + start.character as usize;
-
-                        if start < end {
-                            let end_offset =
-                                rope.line_to_char(end.line as usize) + end.character as usize;
-                            rope.remove(start_offset..end_offset);
-                        }
-                        if !change.text.is_empty() {
-                            rope.insert(start_offset, change.text.as_str());
-                        }
-                    }
-                    ch
-                }
-                Some(range) => {
-                    if cfg!(debug_assertions) {
-                        let mut rope = rope.clone();
-                        let mut rope2 = rope.clone();
-                        dbg!(&change);
-                        let ch = with_change(change.text.as_str(), range, &mut rope, char_diff);
-
-                        for change in ch {
-                            dbg!(&change);
-                            with_change(
-                                change.text.as_str(),
-                                change.range.unwrap(),
-                                &mut rope2,
-                                |_, _, _, _, _| (),
-                            );
-                        }
-
-                        debug_assert_eq!(String::from(rope), String::from(rope2));
-                    };
-
-                    if range.start.line == range.end.line
-                        && range.end.character - range.start.character < 3
-                    {
-                        with_change(change.text.as_str(), range, rope, |_, _, _, _, _| {
-                            smallvec![change.clone()]
-                        })
-                    } else {
-                        with_change(change.text.as_str(), range, rope, char_diff)
-                    }
-                }
-            })
-            .collect();
-
-        let did_change = serde_json::to_string(&NotiS::new(Change(DidChangeTextDocumentParams {
-            text_document,
-            content_changes,
-        })))
-        .unwrap();
+        let (old, options) = match applied {
+            None => return Err(Error::Unopened(uri)),
+            Some(Err(range)) => return Err(Error::BadRange(uri, range)),
+            Some(Ok(applied)) => applied,
+        };
 
-        write!(server_stdin, "Content-Length: {}\r\n\r\n", did_change.len()).unwrap();
-        server_stdin.write_all(did_change.as_bytes()).unwrap();
+        let stdin = server.stdin.clone();
+        workers.run(&uri, move || {
+            if let Err(e) = split(old, text_document, content_changes, options, &stdin) {
+                log::warn!("{}", e);
+            }
+        });
+        Ok(())
     };
 
-    let mut stdin = stdin().with_lock();
+    let tick = Duration::from_secs(1);
+    let mut stdin: Box<dyn BufRead> = match args.transport {
+        Transport::Stdio => Box::new(BufReader::new(Ticking::new(io::stdin(), tick))),
+        Transport::Listen(ref addr) => {
+            let client = TcpListener::bind(addr)
+                .and_then(|listener| listener.accept())
+                .and_then(|(client, _)| Ok((client.try_clone()?, client)));
+            let (client, reader) = client.unwrap_or_else(|e| {
+                log::error!("Unable to accept a client on {}: {}", addr, e);
+                fail()
+            });
+            rpc::set_client(Box::new(client));
+            Box::new(BufReader::new(Ticking::new(reader, tick)))
+        }
+    };
 
     let mut buf = Vec::with_capacity(5000);
-    let client_init_params: InitializeParams = loop {
-        let mut msg_len = 0;
-        loop {
-            // TODO Why didn't read_line work?
-            stdin.read_until('\n' as u8, &mut buf).unwrap();
-            if buf == "\r\n".as_bytes() {
-                buf.clear();
-                break;
-            } else {
-                let l = unsafe { str::from_utf8_unchecked(&buf) };
-                if l.starts_with("Content-Length: ") {
-                    dbg!(&l);
-                    msg_len = (&l[16..].lines().next().unwrap())
-                        .parse()
-                        .expect("could not parse len");
-                }
-                buf.clear()
+    let (client_init_params, init_msg): (InitializeParams, Vec<u8>) = loop {
+        match rpc::read_msg(&mut stdin, &mut buf) {
+            Ok(true) => (),
+            Ok(false) => {
+                log::error!("The client left before sending initialize");
+                fail()
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {
+                log::warn!("Dropped a message from the client: {}", e);
+                continue;
+            }
+            Err(e) => {
+                log::error!("{}", Error::Client(e));
+                fail()
             }
         }
-
-        dbg!("Deserializing message");
-        buf.resize(msg_len, 0);
-        let msg = &mut buf[..msg_len];
-        stdin.read_exact(msg).expect("could not read_exact");
+        rpc::trace("from client", &buf);
         if let Ok(Noti {
             params: Init::Init(init),
             ..
-        }) = serde_json::from_slice(msg)
+        }) = serde_json::from_slice(&buf)
         {
-            write!(server_stdin, "Content-Length: {}\r\n\r\n", msg_len).unwrap();
-            server_stdin.write_all(msg).unwrap();
-            server_stdin.flush().unwrap();
-            dbg!("sent InitializeParams");
-            break init;
+            break (init, buf.clone());
         }
     };
 
+    // The workspace root is needed to find the config, so the server starts only now.
+    let config =
+        Config::discover(args.config.as_deref(), &client_init_params).unwrap_or_else(|e| {
+            rpc::show_message(MessageType::Warning, format!("lsp-diff config: {}", e));
+            Config::default()
+        });
+    let mut profiled = Args::default();
+    config.apply(&args.command, &mut profiled);
+    // Options given on the command line win over the config.
+    let args = Args::parse_onto(profiled, cli.into_iter()).unwrap_or_else(|e| {
+        log::error!("{}", e);
+        fail()
+    });
+
+    let mut supervisor = Supervisor::new(&args);
+    supervisor.snapshots = url_text.snapshots();
+    let mut server = supervisor.spawn().unwrap_or_else(|e| {
+        let message = format!(
+            "Unable to start server with command '{}': {}",
+            args.command[0], e
+        );
+        log::error!("{}", message);
+        rpc::show_message(MessageType::Error, message);
+        fail()
+    });
+
+    if let Ok(envelope) = serde_json::from_slice(&init_msg) {
+        supervisor.pending.track(&envelope, &init_msg);
+    }
+    let written = rpc::write_msg(&mut *server.stdin.lock(), &init_msg);
+    if let Err(e) = written {
+        log::warn!("{}", Error::Server(e));
+        if supervisor
+            .crashed(&mut server, &url_text, &client_init_params, "crashed")
+            .is_err()
+        {
+            fail();
+        }
+    } else {
+        log::debug!("sent initialize to {}", args.command[0]);
+    }
+
     handle_rpc_msgs(
         stdin,
-        server_stdin,
+        server,
+        supervisor,
         &mut url_text,
         change,
         open,
         close,
         client_init_params,
+        &args,
+        &config,
     )
 }
-fn handle_rpc_msgs<'l>(
-    mut stdin: LockedResource<Stdin, StdinLock<'l>>,
-    mut server_stdin: ChildStdin,
-    url_text: &mut HashMap<Url, Rope>,
-    mut change: impl FnMut(DidChangeTextDocumentParams, &mut ChildStdin, &mut HashMap<Url, Rope>),
-    open: fn(DidOpenTextDocumentParams, &mut HashMap<Url, Rope>),
-    close: fn(DidCloseTextDocumentParams, &mut HashMap<Url, Rope>),
+
+#[allow(clippy::too_many_arguments)]
+fn handle_rpc_msgs(
+    mut stdin: Box<dyn BufRead>,
+    mut server: Server,
+    mut supervisor: Supervisor,
+    url_text: &mut Documents,
+    mut change: impl FnMut(
+        DidChangeTextDocumentParams,
+        &mut Server,
+        &mut Documents,
+        bool,
+        &Workers,
+    ) -> error::Result<()>,
+    open: fn(OpenParams, &mut Documents, Options),
+    close: fn(DidCloseTextDocumentParams, &mut Documents),
     client_init_params: InitializeParams,
+    args: &Args,
+    config: &Config,
 ) {
     let mut msg_spill = vec![0; 10_000];
+    let workers = Workers::new(args.workers);
 
     let mut last_time = Instant::now();
+    // The client asked the server to shut down, it may exit now.
+    let mut shutdown = false;
+    // Toggled by `lsp-diff.toggleSplitting`.
+    let mut splitting = true;
 
     loop {
-        let buf = stdin.fill_buf().unwrap();
-        let buf = unsafe { str::from_utf8_unchecked(buf) };
-
-        if buf.is_empty() {
-            let now = Instant::now();
-            if now.duration_since(last_time) > Duration::from_secs(10) {
-                last_time = now;
+        let now = Instant::now();
+        if now.duration_since(last_time) > Duration::from_secs(10) {
+            last_time = now;
 
-                let mem_info = sys_info::mem_info().unwrap();
+            let free = sys_info::mem_info().ok().map(|mem_info| {
                 let free = (mem_info.free + mem_info.swap_free) as f64;
                 let total = (mem_info.total + mem_info.swap_total) as f64;
-                if free / total < 0.10 {
-                    // TODO DeDuplicate
-                    // TODO swallow InitializeResult. dup2() should be used
-                    let server = env::args()
-                        .nth(1)
-                        .expect("Provide lsp command as first argument.");
-                    let server = Command::new(&server)
-                        .args(env::args().skip(2))
-                        .stdin(Stdio::piped())
-                        .spawn()
-                        .unwrap_or_else(|_| {
-                            panic!("Unable to start server with command: '{}'", server)
-                        });
-                    server_stdin = server.stdin.expect("server stdin failed");
-
-                    serde_json::to_writer(
-                        &mut server_stdin,
-                        &NotiS::new(Init::Init(client_init_params.clone())),
-                    )
-                    .unwrap();
-                }
+                free / total
+            });
+            if let Some(free) = free {
+                stats::record(|s| s.free_memory.record((free * 100.0) as u64));
+            }
+            shed(url_text, args);
+            if free.is_some_and(|free| free < args.min_free_memory)
+                && !shutdown
+                && supervisor
+                    .restart(&mut server, url_text, &client_init_params)
+                    .is_err()
+                && supervisor
+                    .crashed(&mut server, url_text, &client_init_params, "crashed")
+                    .is_err()
+            {
+                fail();
             }
-            thread::sleep(Duration::from_millis(40));
-            continue;
         }
-        let (content_len, end_ptr) =
-            buf.lines()
-                .take_while(|l| !l.is_empty())
-                .fold((0, 0), |(con_len, _), l| {
-                    (
-                        if l.starts_with("Content-Length: ") {
-                            (&l[16..]).parse().unwrap()
-                        } else {
-                            con_len
-                        },
-                        l.as_ptr() as usize + l.len(),
-                    )
-                });
-
-        // Headers are terminated with \r\n\r\n
-        let header_end = (end_ptr + 4) - buf.as_ptr() as usize;
-
-        if buf.len() >= header_end + content_len {
-            // We have the whole message.
-            let consume = header_end + content_len;
-            let mut send = || server_stdin.write_all(&buf.as_bytes()[..consume]).unwrap();
-            match serde_json::from_str(&buf[header_end..consume]) {
-                Ok(Change(c)) => change(c, &mut server_stdin, url_text),
-                Ok(Open(o)) => {
-                    send();
-                    open(o, url_text);
-                }
-                Ok(Close(c)) => {
-                    send();
-                    close(c, url_text);
+
+        // Also runs while the client waits on a hung server without sending anything.
+        if !shutdown
+            && supervisor.is_hung(&server)
+            && supervisor
+                .crashed(
+                    &mut server,
+                    url_text,
+                    &client_init_params,
+                    "stopped responding",
+                )
+                .is_err()
+        {
+            fail();
+        }
+
+        let buf = match stdin.fill_buf() {
+            Ok(buf) => buf,
+            // The client has been quiet for a tick, the checks above ran again.
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                log::error!("{}", Error::Client(e));
+                exit(server, shutdown)
+            }
+        };
+
+        if buf.is_empty() {
+            // The client closed stdin without an `exit`, there is no one left to serve.
+            exit(server, shutdown);
+        }
+
+        // Bytes of the message already read into `msg_spill`.
+        let mut spilled = 0;
+        let (buf, framed) = match frame(buf) {
+            Ok(None) => {
+                // The header is split across reads, so it is read on its own.
+                msg_spill.clear();
+                match rpc::read_header(&mut stdin, &mut msg_spill) {
+                    Ok(true) => (),
+                    Ok(false) => exit(server, shutdown),
+                    Err(e) => {
+                        log::error!("{}", Error::Client(e));
+                        exit(server, shutdown)
+                    }
                 }
-                _ => send(),
+                spilled = msg_spill.len();
+                let framed = frame(&msg_spill).and_then(|f| f.ok_or_else(|| header(&msg_spill)));
+                (&[][..], framed)
             }
-            stdin.consume(consume);
+            framed => (buf, framed.map(Option::unwrap)),
+        };
+        let (header_end, content_len) = match framed {
+            Ok(frame) => frame,
+            Err(e) => {
+                log::warn!("{}", e);
+                rpc::show_message(
+                    MessageType::Warning,
+                    format!("lsp-diff dropped a message: {}", e),
+                );
+                let skip = skip(buf);
+                stdin.consume(skip);
+                continue;
+            }
+        };
+
+        let consume = header_end + content_len;
+        let buffered = buf.len() >= consume;
+        // Messages too big for the buffer that lsp-diff has no use for are streamed through.
+        let streamed = if buffered || spilled > 0 || rpc::tracing() {
+            None
         } else {
-            msg_spill.resize(header_end + content_len, 0);
-            let msg = &mut msg_spill[..header_end + content_len];
-            stdin.read_exact(msg).unwrap();
-
-            // duplicated match is needed to convince borrow checker.
-            let mut send = || server_stdin.write_all(msg).unwrap();
-            match serde_json::from_slice(&msg[header_end..]) {
-                Ok(Change(c)) => change(c, &mut server_stdin, url_text),
-                Ok(Open(o)) => {
-                    send();
-                    open(o, url_text);
+            rpc::peek(&buf[header_end..])
+                .filter(|e| e.method.as_ref().is_some_and(|m| !reads_body(m)))
+        };
+        let (envelope, sent) = match streamed {
+            Some(envelope) => {
+                log::trace!("streaming {} bytes of {:?}", consume, envelope.method);
+                supervisor.pending.track(&envelope, &[]);
+                // Without a document to go by it has to wait for everything before it.
+                workers.wait();
+                let streamed = stream(&mut *stdin, &mut *server.stdin.lock(), consume);
+                match streamed {
+                    Err(Error::Client(e)) => {
+                        log::error!("{}", Error::Client(e));
+                        exit(server, shutdown)
+                    }
+                    sent => (Some(envelope), sent),
                 }
-                Ok(Close(c)) => {
-                    send();
-                    close(c, url_text);
+            }
+            None => {
+                let msg = if buffered {
+                    // We have the whole message.
+                    &buf[..consume]
+                } else {
+                    msg_spill.resize(consume, 0);
+                    if let Err(e) = stdin.read_exact(&mut msg_spill[spilled..consume]) {
+                        log::error!("{}", Error::Client(e));
+                        exit(server, shutdown)
+                    }
+                    &msg_spill[..consume]
+                };
+                let body = &msg[header_end..];
+                rpc::trace("from client", body);
+
+                let envelope: Option<Envelope> = serde_json::from_slice(body).ok();
+                let sent = match envelope.as_ref().and_then(|e| commands::parse(e, body)) {
+                    Some((id, Err(message))) => {
+                        let resp = ErrResp::new(&id, rpc::INVALID_PARAMS, message);
+                        let _ = rpc::send_client(&serde_json::to_vec(&resp).unwrap());
+                        Ok(())
+                    }
+                    Some((id, Ok(command))) => {
+                        workers.wait();
+                        let result = match command {
+                            Command::RestartServer => {
+                                if supervisor
+                                    .restart(&mut server, url_text, &client_init_params)
+                                    .is_err()
+                                    && supervisor
+                                        .crashed(
+                                            &mut server,
+                                            url_text,
+                                            &client_init_params,
+                                            "crashed",
+                                        )
+                                        .is_err()
+                                {
+                                    fail();
+                                }
+                                Value::Null
+                            }
+                            Command::ResyncDocument(uri) => {
+                                for (u, doc) in url_text.iter() {
+                                    if uri.as_ref().is_none_or(|uri| uri == u) {
+                                        // A failure is noticed by the crash check below.
+                                        let _ = server.send(&NotiS::new(Change(doc.resync(u))));
+                                    }
+                                }
+                                Value::Null
+                            }
+                            Command::ToggleSplitting => {
+                                splitting = !splitting;
+                                rpc::show_message(
+                                    MessageType::Info,
+                                    format!(
+                                        "lsp-diff change splitting {}.",
+                                        if splitting { "enabled" } else { "disabled" }
+                                    ),
+                                );
+                                Value::Null
+                            }
+                            Command::Stats => serde_json::to_value(stats::snapshot()).unwrap(),
+                        };
+                        let resp = serde_json::to_vec(&Resp::new(&id, result)).unwrap();
+                        let _ = rpc::send_client(&resp);
+                        Ok(())
+                    }
+                    None => {
+                        if let Some(ref e) = envelope {
+                            supervisor.pending.track(e, body);
+                            // The response's edits are relative to the document as it is now.
+                            if let Envelope {
+                                id: Some(ref id),
+                                method: Some(ref method),
+                            } = *e
+                            {
+                                if format::METHODS.contains(&method.as_str()) {
+                                    let doc = rpc::document(body)
+                                        .and_then(|uri| url_text.get(&uri).cloned());
+                                    if let Some(doc) = doc {
+                                        supervisor.pending.attach(id, doc);
+                                    }
+                                }
+                            }
+                        }
+                        let method = envelope.as_ref().and_then(|e| e.method.as_ref());
+                        // Deltas for a server without them are asked for as full tokens.
+                        let tokens_msg = method
+                            .filter(|m| tokens::METHODS.contains(&m.as_str()))
+                            .and_then(|m| supervisor.tokens.request(m, body));
+                        // The client includes the text lsp-diff asked for, the server may not want it.
+                        let save_msg = method
+                            .filter(|&m| m == "textDocument/didSave")
+                            .and_then(|_| supervisor.save_text.strip(body));
+                        let rewritten = tokens_msg.or(save_msg);
+                        let msg = rewritten.as_ref().map_or(msg, Vec::as_slice);
+                        let input = &server.stdin;
+                        let send = |uri: Option<&Url>| forward(&workers, input, uri, msg);
+                        // Only the method decides the route, other messages aren't parsed again.
+                        // A malformed document notification is an error, so it's worth a warning.
+                        match method.map(String::as_str) {
+                            Some("textDocument/didChange") => {
+                                serde_json::from_slice(body).map_err(Error::from).and_then(
+                                    |Params { params }: Params<DidChangeTextDocumentParams>| {
+                                        change(params, &mut server, url_text, splitting, &workers)
+                                    },
+                                )
+                            }
+                            Some("textDocument/didOpen") => serde_json::from_slice(body)
+                                .map_err(Error::from)
+                                .and_then(|Params { params }: Params<OpenParams>| {
+                                    let language_id = &params.text_document.language_id;
+                                    let options = config.options(language_id, args);
+                                    let sent = send(Some(&params.text_document.uri));
+                                    open(params, url_text, options);
+                                    shed(url_text, args);
+                                    sent
+                                }),
+                            Some("textDocument/didClose") => {
+                                serde_json::from_slice(body).map_err(Error::from).and_then(
+                                    |Params { params }: Params<DidCloseTextDocumentParams>| {
+                                        let sent = send(Some(&params.text_document.uri));
+                                        supervisor.tokens.forget(&params.text_document.uri);
+                                        close(params, url_text);
+                                        sent
+                                    },
+                                )
+                            }
+                            Some("textDocument/didSave") => serde_json::from_slice(body)
+                                .map_err(Error::from)
+                                .and_then(|Params { params }: Params<SaveParams>| {
+                                    let uri = params.text_document.uri;
+                                    send(Some(url_text.queue(&uri)))?;
+                                    match params.text {
+                                        Some(text) => saved(uri, &text, url_text),
+                                        None => Ok(()),
+                                    }
+                                }),
+                            Some("notebookDocument/didChange") => {
+                                serde_json::from_slice(body).map_err(Error::from).and_then(
+                                    |Params { params }: Params<DidChangeNotebookDocumentParams>| {
+                                        let options = |lang: &str| config.options(lang, args);
+                                        notebook_change(
+                                            params, &server, url_text, splitting, &workers, options,
+                                        )
+                                    },
+                                )
+                            }
+                            Some("notebookDocument/didOpen") => {
+                                serde_json::from_slice(body).map_err(Error::from).and_then(
+                                    |Params { params }: Params<DidOpenNotebookDocumentParams>| {
+                                        let notebook = params.notebook_document;
+                                        let sent = send(Some(&notebook.uri));
+                                        for item in params.cell_text_documents {
+                                            let options = config.options(&item.language_id, args);
+                                            let uri = item.uri.clone();
+                                            let doc = Document::open(item, options);
+                                            url_text.open_cell(&notebook.uri, uri, doc);
+                                        }
+                                        url_text.open_notebook(notebook);
+                                        shed(url_text, args);
+                                        sent
+                                    },
+                                )
+                            }
+                            Some("notebookDocument/didClose") => {
+                                serde_json::from_slice(body).map_err(Error::from).and_then(
+                                    |Params { params }: Params<DidCloseNotebookDocumentParams>| {
+                                        let sent = send(Some(&params.notebook_document.uri));
+                                        for cell in &params.cell_text_documents {
+                                            url_text.close(&cell.uri);
+                                        }
+                                        url_text.close_notebook(&params.notebook_document.uri);
+                                        sent
+                                    },
+                                )
+                            }
+                            // Only worth parsing if it may have to queue behind a document.
+                            _ if workers.busy() => {
+                                send(rpc::document(body).as_ref().map(|uri| url_text.queue(uri)))
+                            }
+                            _ => send(None),
+                        }
+                    }
+                };
+                let sent = sent.or_else(|e| recover(e, msg, &mut server, url_text, &workers));
+                if buffered {
+                    stdin.consume(consume);
                 }
-                _ => send(),
+                (envelope, sent)
             }
         };
-        server_stdin.flush().unwrap();
+        let sent = sent.and_then(|_| server.stdin.lock().flush().map_err(Error::Server));
+        stats::record(|s| s.documents_bytes = url_text.size() as u64);
+
+        match envelope.and_then(|e| e.method).as_deref() {
+            Some("shutdown") => shutdown = true,
+            Some("exit") => exit(server, shutdown),
+            _ => (),
+        }
+
+        // What's left are server errors, whose policy is a restart.
+        // The message that failed to send is lost, but `url_text` already reflects it,
+        // so the restarted server still gets the current documents.
+        if !shutdown
+            && (sent.is_err() || server.has_exited())
+            && supervisor
+                .crashed(&mut server, url_text, &client_init_params, "crashed")
+                .is_err()
+        {
+            fail();
+        }
     }
 }
 
-fn with_change<R: std::fmt::Debug>(
-    change_text: &str,
-    range: Range,
-    rope: &mut Rope,
-    with: impl Fn(&mut Rope, Range, &str, usize, usize) -> R,
-) -> R {
-    let start_offset =
-        rope.line_to_char(range.start.line as usize) + range.start.character as usize;
-    let end_offset = rope.line_to_char(range.end.line as usize) + range.end.character as usize;
-    let ret = with(rope, range, change_text, start_offset, end_offset);
-    // TODO Handle Unicode consistently.
-    dbg!("\n\n\nChange\n");
-    if start_offset < end_offset {
-        dbg!("START_OFFSET < END_OFFSET");
-        dbg!(&rope.line(dbg!(range.start.line) as usize));
-        rope.remove(start_offset..end_offset);
-        dbg!(&rope.line(range.start.line as usize));
+/// Lets the server finish exiting and exits the proxy with the status the spec asks of a server:
+/// 0 if the client sent `shutdown` first, 1 otherwise.
+fn exit(server: Server, shutdown: bool) -> ! {
+    stats::log();
+    server.wait_or_kill(Duration::from_secs(5));
+    process::exit(if shutdown { 0 } else { 1 })
+}
+
+/// Exits with 1 after logging the stats, for when lsp-diff can't go on.
+fn fail() -> ! {
+    stats::log();
+    process::exit(1)
+}
+
+/// Carries on after `err` the way its policy says.
+/// `msg` is the client's message, headers included. Errors that need a restart are returned.
+fn recover(
+    err: Error,
+    msg: &[u8],
+    server: &mut Server,
+    url_text: &mut Documents,
+    workers: &Workers,
+) -> error::Result<()> {
+    match err {
+        Error::Dropped(_) => log::debug!("{}", err),
+        _ => log::warn!("{}", err),
     }
-    if !change_text.is_empty() {
-        dbg!("CHANGE_TEXT NOT EMPTY");
-        dbg!(range.start.line);
-        dbg!(&rope.line(range.start.line as usize));
-        rope.insert(start_offset, change_text);
-        dbg!(&rope.line(range.start.line as usize));
+    match err.policy() {
+        Policy::Forward => {
+            let uri = match err {
+                Error::BadRange(ref uri, _) => {
+                    // Our copy can't be trusted anymore, its changes are forwarded as is from now on.
+                    url_text.drop_text(uri);
+                    Some(uri)
+                }
+                Error::Unopened(ref uri) | Error::Dropped(ref uri) => Some(uri),
+                _ => None,
+            };
+            let uri = uri.map(|uri| url_text.queue(uri));
+            forward(workers, &server.stdin, uri, msg)
+        }
+        Policy::Resync => {
+            workers.wait();
+            if let Error::Unsaved(ref uri) = err {
+                if let Some(doc) = url_text.get(uri) {
+                    server
+                        .send(&NotiS::new(Change(doc.resync(uri))))
+                        .map_err(Error::Server)?;
+                }
+            }
+            Ok(())
+        }
+        Policy::Report => {
+            rpc::show_message(MessageType::Warning, format!("lsp-diff: {}", err));
+            Ok(())
+        }
+        Policy::Restart | Policy::Exit => Err(err),
+    }
+}
+
+/// Finds the end of the headers at the start of `buf` and the length of the body after them.
+/// `None` if the headers don't end in `buf`.
+fn frame(buf: &[u8]) -> error::Result<Option<(usize, usize)>> {
+    // Headers are terminated with \r\n\r\n
+    let end = match buf.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(i) => i + 4,
+        None => return Ok(None),
     };
+    let mut content_len = None;
+    for l in buf[..end].split(|&b| b == b'\n') {
+        if l.starts_with(b"Content-Length: ") {
+            let len = str::from_utf8(&l[16..])
+                .ok()
+                .and_then(|n| n.trim_end().parse().ok());
+            content_len = Some(len.ok_or_else(|| header(l))?);
+        }
+    }
+    content_len
+        .map(|len| Some((end, len)))
+        .ok_or_else(|| header(&buf[..end]))
+}
 
-    dbg!("END\n");
-    ret
+/// The error for a malformed header, showing its first line.
+fn header(buf: &[u8]) -> Error {
+    let line = buf.split(|&b| b == b'\n').next().unwrap_or_default();
+    Error::Header(String::from_utf8_lossy(line).into_owned())
 }
 
-fn char_diff(
-    rope: &mut Rope,
-    range: Range,
-    change_text: &str,
-    start_offset: usize,
-    end_offset: usize,
+/// How much of `buf` to skip after a malformed header, to what looks like the next message.
+fn skip(buf: &[u8]) -> usize {
+    buf.get(1..)
+        .and_then(|rest| rest.windows(16).position(|w| w == b"Content-Length: "))
+        .map_or(buf.len(), |i| i + 1)
+}
+
+/// Runs `split`, a diff engine splitting `change` to `doc`, catching its panics and checking that
+/// its changes have the effect of `change`. If they don't, `change` is forwarded as is and its
+/// input saved for a bug report.
+fn isolate(
+    uri: &Url,
+    doc: &Document,
+    change: &TextDocumentContentChangeEvent,
+    split: impl FnOnce() -> Changes,
 ) -> Changes {
-    let old_slice = RopeSlice {
-        slice: rope.slice(start_offset..end_offset),
-        absolute_pos: ropey::Position {
-            line: rope.char_to_line(start_offset),
-            character: dbg!(range.start.character) as usize,
-        },
+    let histogram: fn(&mut stats::Stats) -> &mut stats::Histogram = if change.range.is_some() {
+        |s| &mut s.incremental
+    } else {
+        |s| &mut s.full
+    };
+    let checked = || {
+        let changes = stats::time(histogram, split);
+        let mut split_text = doc.buffer.clone();
+        for c in &changes {
+            log::trace!("split into {:?}", c);
+            lsp_diff::apply_change(&mut split_text, c);
+        }
+        let mut text = doc.buffer.clone();
+        lsp_diff::apply_change(&mut text, change);
+        if split_text == text {
+            Ok(changes)
+        } else {
+            Err("the split changes diverged".to_owned())
+        }
     };
+    let reason = match panic::catch_unwind(AssertUnwindSafe(checked)) {
+        Ok(Ok(changes)) => return changes,
+        Ok(Err(reason)) => reason,
+        Err(payload) => payload
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_default(),
+    };
+    save_input(uri, &doc.buffer, change, &reason);
+    stats::record(|s| s.fallbacks += 1);
+    smallvec![change.clone()]
+}
 
-    Incremental::diff(old_slice, change_text)
+/// Writes what a diff engine failed on to a file in the temp dir and logs where.
+fn save_input(uri: &Url, old: &Rope, change: &TextDocumentContentChangeEvent, reason: &str) {
+    static SAVED: AtomicUsize = AtomicUsize::new(0);
+    let path = env::temp_dir().join(format!(
+        "lsp-diff-{}-failed-diff-{}.json",
+        process::id(),
+        SAVED.fetch_add(1, Ordering::SeqCst)
+    ));
+    let input = json!({
+        "uri": uri.as_str(),
+        "reason": reason,
+        "old": old.to_string(),
+        "change": change,
+    });
+    match fs::write(&path, input.to_string()) {
+        Ok(()) => log::error!(
+            "Splitting a change to {} failed: {}. Its input is in {}",
+            uri,
+            reason,
+            path.display()
+        ),
+        Err(e) => log::error!(
+            "Splitting a change to {} failed: {}. Saving its input failed: {}",
+            uri,
+            reason,
+            e
+        ),
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug)]
 #[serde(tag = "method", content = "params")]
+#[allow(clippy::large_enum_variant)]
 enum Init {
     #[serde(rename = "initialize")]
     Init(InitializeParams),
+    #[serde(rename = "initialized")]
+    Initialized(InitializedParams),
+}
+
+/// Only documents at least this big are dropped from the store, smaller ones cost little.
+const LARGE_DOCUMENT: usize = 1 << 20;
+
+/// Drops the largest documents from `url_text` while it holds more than `--store-limit`.
+fn shed(url_text: &mut Documents, args: &Args) {
+    if url_text.size() <= args.store_limit {
+        return;
+    }
+    let dropped = url_text.shed(args.store_limit, LARGE_DOCUMENT);
+    for uri in &dropped {
+        log::info!("{}", Error::Dropped(uri.clone()));
+    }
+    stats::record(|s| s.documents_dropped += dropped.len() as u64);
 }
 
+/// Writes `msg` to the server after the jobs queued for `uri`, or for every document without one.
+fn forward(
+    workers: &Workers,
+    stdin: &Arc<Input>,
+    uri: Option<&Url>,
+    msg: &[u8],
+) -> error::Result<()> {
+    match uri {
+        Some(uri) if workers.pending(uri) => {
+            let (stdin, msg) = (stdin.clone(), msg.to_vec());
+            workers.run(uri, move || {
+                if let Err(e) = stdin.lock().write_all(&msg) {
+                    log::warn!("{}", Error::Server(e));
+                }
+            });
+            return Ok(());
+        }
+        Some(_) => (),
+        None => workers.wait(),
+    }
+    stdin.lock().write_all(msg).map_err(Error::Server)
+}
+
+/// Splits `content_changes` against `doc`, the document before them, and sends the result.
+fn split(
+    mut doc: Document,
+    text_document: VersionedTextDocumentIdentifier,
+    content_changes: Vec<TextDocumentContentChangeEvent>,
+    options: Options,
+    stdin: &Input,
+) -> error::Result<()> {
+    let uri = text_document.uri.clone();
+    let split_changes = split_changes(&mut doc, &uri, content_changes, options);
+    stdin
+        .send(&NotiS::new(Change(DidChangeTextDocumentParams {
+            text_document,
+            content_changes: split_changes,
+        })))
+        .map_err(Error::Server)
+}
+
+/// Applies `content_changes` to `doc`, returning the changes they split into.
+fn split_changes(
+    doc: &mut Document,
+    uri: &Url,
+    content_changes: Vec<TextDocumentContentChangeEvent>,
+    options: Options,
+) -> Vec<TextDocumentContentChangeEvent> {
+    let received = content_changes.len() as u64;
+    let received_bytes: usize = content_changes.iter().map(|c| c.text.len()).sum();
+    let mut split_changes = Vec::with_capacity(content_changes.len());
+    for change in content_changes {
+        if !options.splits(&change) {
+            doc.apply(&change);
+            split_changes.push(change);
+            continue;
+        }
+
+        log::trace!("splitting {:?}", change);
+        let ch = isolate(uri, doc, &change, || {
+            lsp_diff::split_change_hashed(&doc.buffer, &doc.hashes, &change, options)
+        });
+        doc.apply(&change);
+        split_changes.extend(ch);
+    }
+
+    let emitted_bytes: usize = split_changes.iter().map(|c| c.text.len()).sum();
+    stats::record(|s| {
+        s.changes_received += received;
+        s.changes_emitted += split_changes.len() as u64;
+        s.bytes_saved += received_bytes as i64 - emitted_bytes as i64;
+    });
+    split_changes
+}
+
+/// Applies `changes` to `doc`, returning the document before them and the options to split them
+/// with. Errs with the range of a change that doesn't fit.
+fn prepare(
+    doc: &mut Document,
+    version: Option<u64>,
+    changes: &[TextDocumentContentChangeEvent],
+    splitting: bool,
+) -> Result<(Document, Options), Range> {
+    if let Some(version) = version {
+        doc.version = version;
+    }
+    let mut options = doc.options;
+    if !splitting {
+        options.split = Split::Off;
+    }
+    // The copy of the rope shares its nodes, only the line hashes are copied.
+    let old = doc.clone();
+    for change in changes {
+        if let Some(range) = change.range {
+            if !lsp_diff::contains(&doc.buffer, range) {
+                return Err(range);
+            }
+        }
+        doc.apply(change);
+    }
+    Ok((old, options))
+}
+
+/// Applies a notebook change to `url_text`, then has a worker split the cells' text changes
+/// and send it on.
+/// Cells lsp-diff has no usable copy of keep their changes as they are.
+fn notebook_change(
+    params: DidChangeNotebookDocumentParams,
+    server: &Server,
+    url_text: &mut Documents,
+    splitting: bool,
+    workers: &Workers,
+    options: impl Fn(&str) -> Options,
+) -> error::Result<()> {
+    let uri = params.notebook_document.uri.clone();
+    match url_text.notebook_mut(&uri) {
+        Some(notebook) => notebook.apply(params.notebook_document.version, &params.change),
+        None => return Err(Error::Unopened(uri)),
+    }
+
+    let mut olds = Vec::new();
+    if let Some(ref cells) = params.change.cells {
+        if let Some(ref structure) = cells.structure {
+            for cell in structure.did_close.iter().flatten() {
+                url_text.close(&cell.uri);
+            }
+            for item in structure.did_open.iter().flatten() {
+                let doc = Document::new(item.clone(), options(&item.language_id));
+                url_text.open_cell(&uri, item.uri.clone(), doc);
+            }
+        }
+        for text in cells.text_content.iter().flatten() {
+            let cell = &text.document.uri;
+            let version = text.document.version;
+            let old =
+                match url_text.edit(cell, |doc| prepare(doc, version, &text.changes, splitting)) {
+                    Some(Ok(old)) => Some(old),
+                    Some(Err(range)) => {
+                        log::warn!("{}", Error::BadRange(cell.clone(), range));
+                        url_text.drop_text(cell);
+                        None
+                    }
+                    None => {
+                        url_text.edit_spilled(cell, version, &text.changes);
+                        None
+                    }
+                };
+            olds.push(old);
+        }
+    }
+
+    let stdin = server.stdin.clone();
+    workers.run(&uri, move || {
+        let mut params = params;
+        let texts = params
+            .change
+            .cells
+            .as_mut()
+            .and_then(|cells| cells.text_content.as_mut());
+        for (text, old) in texts.into_iter().flatten().zip(olds) {
+            if let Some((mut doc, options)) = old {
+                let changes = mem::take(&mut text.changes);
+                text.changes = split_changes(&mut doc, &text.document.uri, changes, options);
+            }
+        }
+        if let Err(e) = stdin.send(&NotiS::new(NotebookDid::Change(params))) {
+            log::warn!("{}", Error::Server(e));
+        }
+    });
+    Ok(())
+}
+
+/// Checks lsp-diff's copy of `uri` against the text the client saved.
+/// If they differ the saved text replaces the copy, which is then resynced.
+fn saved(uri: Url, text: &str, url_text: &mut Documents) -> error::Result<()> {
+    let differs = url_text.edit(&uri, |doc| {
+        let differs = !doc.matches(text);
+        if differs {
+            doc.set_text(text);
+        }
+        differs
+    });
+    if differs == Some(true) {
+        stats::record(|s| s.saves_diverged += 1);
+        return Err(Error::Unsaved(uri));
+    }
+    Ok(())
+}
+
+/// Whether lsp-diff reads the body of messages with `method`, so they can't be streamed through.
+fn reads_body(method: &str) -> bool {
+    DID_METHODS.contains(&method)
+        || method == "textDocument/didSave"
+        || NOTEBOOK_METHODS.contains(&method)
+        || tokens::METHODS.contains(&method)
+        || commands::handles(method)
+        || pending::needs_body(method)
+}
+
+/// Copies the next `len` bytes of `from` to `to` a buffer at a time.
+/// All of them are read even if writing fails, so `from` stays at a message boundary.
+fn stream(from: &mut dyn BufRead, to: &mut dyn Write, mut len: usize) -> error::Result<()> {
+    let mut written = Ok(());
+    while len > 0 {
+        let chunk = match from.fill_buf() {
+            Ok(chunk) => chunk,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(Error::Client(e)),
+        };
+        if chunk.is_empty() {
+            return Err(Error::Client(io::ErrorKind::UnexpectedEof.into()));
+        }
+        let n = chunk.len().min(len);
+        if written.is_ok() {
+            written = to.write_all(&chunk[..n]).map_err(Error::Server);
+        }
+        from.consume(n);
+        len -= n;
+    }
+    written
+}
+
+/// The methods of `Did`.
+const DID_METHODS: &[&str] = &[
+    "textDocument/didChange",
+    "textDocument/didOpen",
+    "textDocument/didClose",
+];
+
 #[derive(Deserialize, Serialize, Debug)]
 #[serde(tag = "method", content = "params")]
 enum Did {
@@ -343,31 +1008,81 @@ enum Did {
 }
 use Did::*;
 
-#[derive(Deserialize, Debug)]
-struct Noti<M> {
-    jsonrpc: IgnoredAny,
-    #[serde(flatten)]
-    params: M,
+#[test]
+fn parse_test() {
+    let line = r#"{"jsonrpc":"2.0","method":"textDocument/didChange","params":{"textDocument":{"version":2,"uri":"file:///home/host/haskell-ide-engine/src/Haskell/Ide/Engine/Channel.hs"},"contentChanges":[{"range":{"start":{"line":25,"character":0},"end":{"line":25,"character":1}},"rangeLength":1,"text":"l"}]}}"#;
+    let _: Noti<Did> = serde_json::from_str(line).unwrap();
 }
 
-#[derive(Deserialize, Serialize, Debug)]
-struct NotiS<M> {
-    jsonrpc: &'static str,
-    #[serde(flatten)]
-    params: M,
+#[test]
+fn frame_test() {
+    let msg = b"Content-Length: 2\r\n\r\n{}";
+    assert_eq!(frame(msg).unwrap(), Some((21, 2)));
+
+    // A header split across reads.
+    let mut client = BufReader::with_capacity(10, &msg[..]);
+    assert_eq!(frame(client.fill_buf().unwrap()).unwrap(), None);
+    let mut spill = Vec::new();
+    assert!(rpc::read_header(&mut client, &mut spill).unwrap());
+    assert_eq!(frame(&spill).unwrap(), Some((21, 2)));
+    let mut body = [0; 2];
+    client.read_exact(&mut body).unwrap();
+    assert_eq!(&body, b"{}");
+
+    // After a malformed header the next message is found.
+    let msgs = b"Content-Length: x\r\n\r\n{}Content-Length: 2\r\n\r\n{}";
+    assert!(frame(msgs).is_err());
+    assert_eq!(frame(&msgs[skip(msgs)..]).unwrap(), Some((21, 2)));
+    assert!(frame(b"Content-Type: json\r\n\r\n").is_err());
 }
 
-impl<M> NotiS<M> {
-    fn new(params: M) -> Self {
-        NotiS {
-            jsonrpc: "2.0",
-            params,
-        }
+#[test]
+fn isolate_test() {
+    let uri = Url::parse("file:///a.txt").unwrap();
+    let item = TextDocumentItem::new(uri.clone(), "plaintext".to_owned(), 1, "abc".to_owned());
+    let doc = Document::new(item, Options::default());
+    let change = TextDocumentContentChangeEvent {
+        range: None,
+        range_length: None,
+        text: "abd".to_owned(),
+    };
+
+    let panicking = isolate(&uri, &doc, &change, || panic!("engine bug"));
+    assert_eq!(panicking.as_slice(), std::slice::from_ref(&change));
+    let diverging = isolate(&uri, &doc, &change, Changes::new);
+    assert_eq!(diverging.as_slice(), std::slice::from_ref(&change));
+    let split = isolate(&uri, &doc, &change, || {
+        lsp_diff::split_change(&doc.buffer, &change, Options::default())
+    });
+    assert!(split.iter().all(|c| c.range.is_some()));
+    let mut buffer = doc.buffer.clone();
+    for c in &split {
+        lsp_diff::apply_change(&mut buffer, c);
     }
+    assert_eq!(buffer.to_string(), "abd");
 }
 
 #[test]
-fn parse_test() {
-    let line = r#"{"jsonrpc":"2.0","method":"textDocument/didChange","params":{"textDocument":{"version":2,"uri":"file:///home/host/haskell-ide-engine/src/Haskell/Ide/Engine/Channel.hs"},"contentChanges":[{"range":{"start":{"line":25,"character":0},"end":{"line":25,"character":1}},"rangeLength":1,"text":"l"}]}}"#;
-    let _: Noti<Did> = serde_json::from_str(line).unwrap();
+fn saved_test() {
+    let uri = Url::parse("file:///a.txt").unwrap();
+    let item = TextDocumentItem::new(uri.clone(), "plaintext".to_owned(), 3, "abc".to_owned());
+    let mut url_text: Documents = Documents::new();
+    url_text.open(uri.clone(), Document::new(item, Options::default()));
+    let diverged = stats::snapshot().saves_diverged;
+
+    assert!(saved(uri.clone(), "abc", &mut url_text).is_ok());
+    assert_eq!(stats::snapshot().saves_diverged, diverged);
+
+    match saved(uri.clone(), "abd", &mut url_text) {
+        Err(ref e @ Error::Unsaved(_)) => assert_eq!(e.policy(), Policy::Resync),
+        r => panic!("{:?}", r),
+    }
+    assert_eq!(stats::snapshot().saves_diverged, diverged + 1);
+    let resync = url_text.get(&uri).unwrap().resync(&uri);
+    assert_eq!(resync.text_document.version, Some(3));
+    assert_eq!(resync.content_changes[0].text, "abd");
+
+    url_text.drop_text(&uri);
+    assert!(saved(uri, "xyz", &mut url_text).is_ok());
+    assert_eq!(stats::snapshot().saves_diverged, diverged + 1);
 }
@@ -0,0 +1,181 @@
+//! LSP 3.17 notebook document sync, which lsp-types doesn't have yet.
+//! Only what lsp-diff looks at is typed, everything else is passed through as is.
+
+use lsp_types::*;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::document::OpenItem;
+
+pub const NOTEBOOK_METHODS: &[&str] = &[
+    "notebookDocument/didOpen",
+    "notebookDocument/didChange",
+    "notebookDocument/didClose",
+];
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct NotebookDocument {
+    #[serde(with = "url_serde")]
+    pub uri: Url,
+    pub notebook_type: String,
+    pub version: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Value>,
+    pub cells: Vec<NotebookCell>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct NotebookCell {
+    /// The cell's text document.
+    #[serde(with = "url_serde")]
+    pub document: Url,
+    /// `kind`, `metadata` and `executionSummary`.
+    #[serde(flatten)]
+    pub rest: Map<String, Value>,
+}
+
+impl NotebookDocument {
+    /// Applies everything but the cells' text to the notebook.
+    pub fn apply(&mut self, version: i64, change: &NotebookDocumentChangeEvent) {
+        self.version = version;
+        if let Some(ref metadata) = change.metadata {
+            self.metadata = Some(metadata.clone());
+        }
+        let cells = match change.cells {
+            Some(ref cells) => cells,
+            None => return,
+        };
+        if let Some(ref structure) = cells.structure {
+            let NotebookCellArrayChange {
+                start,
+                delete_count,
+                ref cells,
+            } = structure.array;
+            let end = (start + delete_count).min(self.cells.len());
+            let start = start.min(end);
+            self.cells
+                .splice(start..end, cells.iter().flatten().cloned());
+        }
+        for cell in cells.data.iter().flatten() {
+            if let Some(c) = self.cells.iter_mut().find(|c| c.document == cell.document) {
+                *c = cell.clone();
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DidOpenNotebookDocumentParams {
+    pub notebook_document: NotebookDocument,
+    pub cell_text_documents: Vec<OpenItem>,
+}
+
+/// `notebookDocument/didOpen` for a restarted server.
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct NotebookOpened<'a> {
+    pub notebook_document: &'a NotebookDocument,
+    pub cell_text_documents: Vec<TextDocumentItem>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DidChangeNotebookDocumentParams {
+    pub notebook_document: VersionedNotebookDocumentIdentifier,
+    pub change: NotebookDocumentChangeEvent,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct VersionedNotebookDocumentIdentifier {
+    pub version: i64,
+    #[serde(with = "url_serde")]
+    pub uri: Url,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct NotebookDocumentChangeEvent {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cells: Option<NotebookCellsChange>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct NotebookCellsChange {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub structure: Option<CellStructureChange>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Vec<NotebookCell>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub text_content: Option<Vec<CellTextChange>>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CellStructureChange {
+    pub array: NotebookCellArrayChange,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub did_open: Option<Vec<TextDocumentItem>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub did_close: Option<Vec<TextDocumentIdentifier>>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct NotebookCellArrayChange {
+    pub start: usize,
+    pub delete_count: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cells: Option<Vec<NotebookCell>>,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct CellTextChange {
+    pub document: VersionedTextDocumentIdentifier,
+    pub changes: Vec<TextDocumentContentChangeEvent>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DidCloseNotebookDocumentParams {
+    pub notebook_document: TextDocumentIdentifier,
+    pub cell_text_documents: Vec<TextDocumentIdentifier>,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "method", content = "params")]
+#[allow(clippy::large_enum_variant)]
+pub enum NotebookDid<'a> {
+    #[serde(rename = "notebookDocument/didOpen")]
+    Open(NotebookOpened<'a>),
+    #[serde(rename = "notebookDocument/didChange")]
+    Change(DidChangeNotebookDocumentParams),
+}
+
+#[test]
+fn notebook_change_test() {
+    let mut notebook: NotebookDocument = serde_json::from_str(
+        r#"{"uri":"file:///n.ipynb","notebookType":"jupyter-notebook","version":1,
+            "cells":[{"kind":2,"document":"file:///n.ipynb#a"},{"kind":2,"document":"file:///n.ipynb#b"}]}"#,
+    )
+    .unwrap();
+    let params: DidChangeNotebookDocumentParams = serde_json::from_str(
+        r#"{"notebookDocument":{"version":2,"uri":"file:///n.ipynb"},"change":{"cells":{
+            "structure":{"array":{"start":1,"deleteCount":1,"cells":[{"kind":1,"document":"file:///n.ipynb#c"}]}},
+            "data":[{"kind":2,"document":"file:///n.ipynb#a","metadata":{"x":1}}]}}}"#,
+    )
+    .unwrap();
+    notebook.apply(params.notebook_document.version, &params.change);
+
+    assert_eq!(notebook.version, 2);
+    let cells: Vec<_> = notebook
+        .cells
+        .iter()
+        .map(|c| c.document.fragment())
+        .collect();
+    assert_eq!(cells, vec![Some("a"), Some("c")]);
+    assert_eq!(notebook.cells[0].rest["metadata"]["x"], 1);
+}
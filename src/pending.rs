@@ -0,0 +1,161 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::document::Document;
+use crate::format;
+use crate::rpc::{self, Envelope, ErrResp, Noti};
+use crate::server::Server;
+
+/// Requests that don't change server state, so asking a restarted server again is harmless.
+const IDEMPOTENT: &[&str] = &[
+    "textDocument/hover",
+    "textDocument/completion",
+    "textDocument/signatureHelp",
+    "textDocument/definition",
+    "textDocument/declaration",
+    "textDocument/typeDefinition",
+    "textDocument/implementation",
+    "textDocument/references",
+    "textDocument/documentHighlight",
+    "textDocument/documentSymbol",
+    "textDocument/documentLink",
+    "textDocument/codeLens",
+    "textDocument/codeAction",
+    "textDocument/foldingRange",
+    "workspace/symbol",
+];
+
+/// Whether tracking a message with `method` needs its whole body.
+pub fn needs_body(method: &str) -> bool {
+    IDEMPOTENT.contains(&method) || format::METHODS.contains(&method) || method == "$/cancelRequest"
+}
+
+pub struct Request {
+    pub id: Value,
+    pub method: String,
+    /// When it was last sent to a server.
+    pub sent: Instant,
+    /// The client sent `$/cancelRequest` for it.
+    pub cancelled: bool,
+    /// Kept for idempotent requests, so they can be re-issued.
+    body: Option<Vec<u8>>,
+    /// The document a formatting request is about, as it was when the request was sent.
+    pub document: Option<Document>,
+}
+
+/// Client requests the server hasn't answered yet.
+/// Shared with the thread forwarding server output, which removes answered requests.
+#[derive(Clone, Default)]
+pub struct Pending(Arc<Mutex<HashMap<String, Request>>>);
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "method", content = "params")]
+enum Cancel {
+    #[serde(rename = "$/cancelRequest")]
+    Cancel { id: Value },
+}
+
+impl Pending {
+    /// Records a client message about to be sent to the server.
+    /// Must happen before it is sent, or the response may beat us.
+    pub fn track(&self, envelope: &Envelope, body: &[u8]) {
+        match *envelope {
+            Envelope {
+                id: Some(ref id),
+                method: Some(ref method),
+            } => {
+                let body = if IDEMPOTENT.contains(&method.as_str()) {
+                    Some(body.to_owned())
+                } else {
+                    None
+                };
+                self.0.lock().unwrap().insert(
+                    id.to_string(),
+                    Request {
+                        id: id.clone(),
+                        method: method.clone(),
+                        sent: Instant::now(),
+                        cancelled: false,
+                        body,
+                        document: None,
+                    },
+                );
+            }
+            Envelope {
+                id: None,
+                method: Some(ref method),
+            } if method == "$/cancelRequest" => {
+                if let Ok(Noti {
+                    params: Cancel::Cancel { id },
+                    ..
+                }) = serde_json::from_slice(body)
+                {
+                    if let Some(req) = self.0.lock().unwrap().get_mut(&id.to_string()) {
+                        req.cancelled = true;
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Keeps `document` with the pending request `id`, for rewriting its response.
+    pub fn attach(&self, id: &Value, document: Document) {
+        if let Some(req) = self.0.lock().unwrap().get_mut(&id.to_string()) {
+            req.document = Some(document);
+        }
+    }
+
+    /// Returns the request `id` answers, if it was pending.
+    pub fn remove(&self, id: &Value) -> Option<Request> {
+        self.0.lock().unwrap().remove(&id.to_string())
+    }
+
+    /// How long the oldest unanswered request has been waiting.
+    pub fn oldest(&self) -> Option<Duration> {
+        self.0
+            .lock()
+            .unwrap()
+            .values()
+            .map(|req| req.sent.elapsed())
+            .max()
+    }
+
+    /// Answers every pending request with an error, so the client isn't left waiting
+    /// on a server that is gone.
+    /// With `reissue` idempotent requests are sent to the new `server` instead.
+    pub fn fail_or_reissue(&self, server: &mut Server, reissue: bool) -> io::Result<()> {
+        let mut reissued = Vec::new();
+        self.0.lock().unwrap().retain(|_, req| match req.body {
+            Some(ref body) if reissue && !req.cancelled => {
+                reissued.push(body.clone());
+                req.sent = Instant::now();
+                true
+            }
+            _ => {
+                let code = if req.cancelled {
+                    rpc::REQUEST_CANCELLED
+                } else {
+                    rpc::CONTENT_MODIFIED
+                };
+                let message = format!("{} interrupted by a server restart", req.method);
+                let resp = serde_json::to_vec(&ErrResp::new(&req.id, code, message)).unwrap();
+                // A vanished client doesn't need its requests answered.
+                let _ = rpc::send_client(&resp);
+                false
+            }
+        });
+
+        // Written without holding the lock, the server's output thread needs it.
+        for body in reissued {
+            rpc::trace("to server", &body);
+            rpc::write_msg(&mut *server.stdin.lock(), &body)?;
+        }
+        Ok(())
+    }
+}
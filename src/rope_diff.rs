@@ -1,136 +1,191 @@
-use crate::chars_diff::{self, Changes, Incremental};
-use diffs::{myers, Diff, Replace};
-use hash_diff::*;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+
+use diffs::{myers, patience, Diff, Replace};
 use lsp_types::{Position, Range, TextDocumentContentChangeEvent};
-use passing_arg_iter::*;
+use serde::Deserialize;
 use smallvec::SmallVec;
 
-#[derive(Debug, Clone)]
-pub struct LineRopeSlice<'a> {
-    pub slice: ropey::RopeSlice<'a>,
-    /// The line index that this slice was created at.
-    pub absolute_index: usize,
+use crate::buffer::{line_ends, TextBuffer};
+use crate::chars_diff::Changes;
+
+/// How `Full` matches up the lines of the old and new text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Algorithm {
+    Myers,
+    /// Anchors on lines that occur once in both texts, which keeps moved blocks readable.
+    Patience,
 }
 
-#[derive(Clone)]
-pub struct Rope(ropey::Rope);
-impl<'a> Segments for &'a Rope {
-    #[allow(clippy::type_complexity)]
-    type Iter = std::iter::Map<
-        std::iter::Enumerate<ropey::iter::Lines<'a>>,
-        fn((usize, ropey::RopeSlice<'a>)) -> LineRopeSlice<'a>,
-    >;
-    type Segment = LineRopeSlice<'a>;
-    fn segments(self) -> Self::Iter {
-        self.0
-            .lines()
-            .enumerate()
-            .map(|(absolute_index, slice)| LineRopeSlice {
-                slice,
-                absolute_index,
-            })
+impl FromStr for Algorithm {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "myers" => Ok(Algorithm::Myers),
+            "patience" => Ok(Algorithm::Patience),
+            _ => Err(format!("unknown diff algorithm '{}'", s)),
+        }
     }
 }
 
-impl<'a> Segments for LineRopeSlice<'a> {
-    #[allow(clippy::type_complexity)]
-    type Iter = std::iter::Map<
-        PassingArgument<std::iter::Enumerate<ropey::iter::Lines<'a>>, usize>,
-        fn((usize, (usize, ropey::RopeSlice<'a>))) -> LineRopeSlice<'a>,
-    >;
-    type Segment = LineRopeSlice<'a>;
-    fn segments(self) -> Self::Iter {
-        fn rs(t: (usize, (usize, ropey::RopeSlice<'_>))) -> LineRopeSlice<'_> {
-            let (offset, (index, slice)) = t;
-            LineRopeSlice {
-                slice,
-                absolute_index: offset + index,
-            }
-        }
+/// Hashes of a buffer's lines, kept up to date as changes are applied
+/// so a full document change only has to hash the new text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LineHashes(Vec<u64>);
 
-        self.slice
-            .lines()
-            .enumerate()
-            .passing_arg(self.absolute_index)
-            .map(rs)
+impl LineHashes {
+    pub fn new<B: TextBuffer>(buffer: &B) -> Self {
+        LineHashes(
+            (0..buffer.len_lines())
+                .map(|i| hash_line(&buffer.line(i)))
+                .collect(),
+        )
     }
-}
 
-impl<'a> ContentPosition for LineRopeSlice<'a> {
-    type Position = usize;
-    fn pos(&self) -> Self::Position {
-        self.absolute_index
+    /// Rehashes the lines `change` touched. `buffer` is the text after `change` was applied.
+    pub fn update<B: TextBuffer>(&mut self, buffer: &B, change: &TextDocumentContentChangeEvent) {
+        let range = match change.range {
+            Some(range) => range,
+            None => {
+                *self = LineHashes::new(buffer);
+                return;
+            }
+        };
+        // A `\n` inserted after a lone `\r` joins that line break, changing the line before.
+        let (start, end) = (
+            (range.start.line as usize).saturating_sub(1),
+            range.end.line as usize,
+        );
+        let inserted = buffer.len_lines() + (end - start + 1) - self.0.len();
+        self.0.splice(
+            start..=end,
+            (start..start + inserted).map(|i| hash_line(&buffer.line(i))),
+        );
     }
-    type Content = ropey::RopeSlice<'a>;
-    fn content(&self) -> Self::Content {
-        self.slice
+
+    pub fn as_slice(&self) -> &[u64] {
+        &self.0
     }
 }
 
-pub struct Full<'o, 'n> {
+/// Turns a full document change into line level changes.
+/// Lines are compared by hash and matched up by `Algorithm`.
+pub struct Full<'n> {
     changes: Changes,
-    old: LineRopeSlice<'o>,
-    new: LineRopeSlice<'n>,
+    old_lines: usize,
+    /// The end of the old text.
+    old_end: Position,
+    new: Vec<&'n str>,
+    /// Lines inserted minus lines deleted by the changes so far.
+    line_offset: isize,
+    /// Whether positions account for the changes before them. If not they are all in the old text.
+    shift: bool,
 }
 
-impl<'o, 'n> Full<'o, 'n> {
-    pub fn diff(old: ropey::RopeSlice<'o>, new: &'n str) -> Changes {
-        let new = LineRopeSlice {
-            slice: new.into(),
-            absolute_index: 0,
-        };
-        let old = LineRopeSlice {
-            slice: old,
-            absolute_index: 0,
-        };
+impl<'n> Full<'n> {
+    /// Changes that turn `old` into `new`. `old_hashes` must be `old`'s.
+    pub fn diff<B: TextBuffer>(
+        old: &B,
+        old_hashes: &LineHashes,
+        new: &str,
+        algorithm: Algorithm,
+    ) -> Changes {
+        Full::run(old, old_hashes, new, algorithm, true)
+    }
 
-        if let Some(hashed) = old.clone().hash_changed(new.clone()) {
-            let mut ld = Full {
-                changes: SmallVec::new(),
-                old,
-                new,
-            };
-
-            myers::diff(
-                &mut Replace::new(&mut ld),
-                &hashed.changed_old,
-                0,
-                hashed.changed_old.len(),
-                &hashed.changed_new,
-                0,
-                hashed.changed_new.len(),
-            )
+    /// Like `diff`, but every change is positioned in `old`, the way `TextEdit`s are.
+    pub fn edits<B: TextBuffer>(
+        old: &B,
+        old_hashes: &LineHashes,
+        new: &str,
+        algorithm: Algorithm,
+    ) -> Changes {
+        Full::run(old, old_hashes, new, algorithm, false)
+    }
+
+    fn run<B: TextBuffer>(
+        old: &B,
+        old_hashes: &LineHashes,
+        new: &str,
+        algorithm: Algorithm,
+        shift: bool,
+    ) -> Changes {
+        let old_hashes = old_hashes.as_slice();
+        let new = lines(new);
+        let new_hashes: Vec<u64> = new.iter().map(|l| hash_line(l)).collect();
+        if old_hashes == &new_hashes[..] {
+            return SmallVec::new();
+        }
+
+        let mut ld = Full {
+            changes: SmallVec::new(),
+            old_lines: old_hashes.len(),
+            old_end: old.char_to_position(old.len_chars()),
+            new,
+            line_offset: 0,
+            shift,
+        };
+        {
+            let (old, new) = (old_hashes, &new_hashes[..]);
+            let d = &mut Replace::new(&mut ld);
+            match algorithm {
+                Algorithm::Myers => myers::diff(d, old, 0, old.len(), new, 0, new.len()),
+                Algorithm::Patience => patience::diff(d, old, 0, old.len(), new, 0, new.len()),
+            }
             .unwrap();
-            ld.changes
+        }
+        ld.changes
+    }
+
+    /// Where the start of old line `line` is after the changes so far.
+    /// One past the last line is the end of the document.
+    fn position(&self, line: usize) -> Position {
+        if line < self.old_lines {
+            Position::new((self.line_offset + line as isize) as u64, 0)
         } else {
-            SmallVec::new()
+            Position::new(
+                (self.line_offset + self.old_end.line as isize) as u64,
+                self.old_end.character,
+            )
+        }
+    }
+
+    fn new_text(&self, new: usize, new_len: usize) -> String {
+        self.new[new..new + new_len].concat()
+    }
+
+    fn shift(&mut self, lines: isize) {
+        if self.shift {
+            self.line_offset += lines;
         }
     }
 }
 
-impl<'o, 'n> Diff for Full<'o, 'n> {
+impl<'n> Diff for Full<'n> {
     type Error = ();
     fn delete(&mut self, old: usize, len: usize) -> Result<(), Self::Error> {
         self.changes.push(TextDocumentContentChangeEvent {
             range: Some(Range {
-                start: Position::new((self.old.absolute_index + old) as u64, 0),
-                end: Position::new((self.old.absolute_index + old + len) as u64, 0),
+                start: self.position(old),
+                end: self.position(old + len),
             }),
             range_length: None,
             text: "".to_owned(),
         });
+        self.shift(-(len as isize));
         Ok(())
     }
 
     fn insert(&mut self, old: usize, new: usize, new_len: usize) -> Result<(), Self::Error> {
-        let text = ropey::RopeSlice::from(self.new.slice.lines().narrow(new..new + new_len));
-        let start = Position::new((self.old.absolute_index + old) as u64, 0);
-
+        let start = self.position(old);
         self.changes.push(TextDocumentContentChangeEvent {
             range: Some(Range { start, end: start }),
             range_length: Some(0),
-            text: text.into(),
+            text: self.new_text(new, new_len),
         });
+        self.shift(new_len as isize);
         Ok(())
     }
 
@@ -141,29 +196,47 @@ impl<'o, 'n> Diff for Full<'o, 'n> {
         new: usize,
         new_len: usize,
     ) -> Result<(), Self::Error> {
-        let text = ropey::RopeSlice::from(self.new.slice.lines().narrow(new..new + new_len));
-
-        Incremental::diff(
-            chars_diff::RopeSlice {
-                slice: self.old.slice.lines().narrow(old..old + old_len).into(),
-                absolute_pos: ropey::Position {
-                    line: self.old.absolute_index + old,
-                    character: 0,
-                },
-            },
-            ropey::RopeSlice::from(self.new.slice.lines().narrow(old..old + old_len))
-                .as_str()
-                .unwrap(),
-        );
-
         self.changes.push(TextDocumentContentChangeEvent {
             range: Some(Range {
-                start: Position::new((self.old.absolute_index + old) as u64, 0),
-                end: Position::new((self.old.absolute_index + old + old_len) as u64, 0),
+                start: self.position(old),
+                end: self.position(old + old_len),
             }),
             range_length: None,
-            text: text.into(),
+            text: self.new_text(new, new_len),
         });
+        self.shift(new_len as isize - old_len as isize);
         Ok(())
     }
 }
+
+/// The lines of `text` with their line breaks, split like `TextBuffer::line`.
+pub fn lines(text: &str) -> Vec<&str> {
+    let mut lines = Vec::with_capacity(text.len() / 40 + 1);
+    let mut start = 0;
+    for end in line_ends(text) {
+        lines.push(&text[start..end]);
+        start = end;
+    }
+    lines.push(&text[start..]);
+    lines
+}
+
+pub fn hash_line(line: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    line.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[test]
+fn line_hashes_test() {
+    let mut text = String::from("a\nb\nc\n");
+    let mut hashes = LineHashes::new(&text);
+    let change = TextDocumentContentChangeEvent {
+        range: Some(Range::new(Position::new(0, 1), Position::new(1, 1))),
+        range_length: None,
+        text: "x\ny\nz".to_owned(),
+    };
+    crate::apply_change(&mut text, &change);
+    hashes.update(&text, &change);
+    assert_eq!(hashes, LineHashes::new(&text));
+}
@@ -0,0 +1,387 @@
+use std::fs::File;
+use std::io::{self, stdout, BufRead, Read, Write};
+use std::str;
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use lazy_static::lazy_static;
+
+use lsp_types::*;
+use serde::{
+    de::{DeserializeOwned, IgnoredAny},
+    Deserialize, Serialize,
+};
+use serde_json::Value;
+
+/// Reads the body of the next message into `buf`.
+/// Returns `Ok(false)` once the stream is closed.
+pub fn read_msg(r: &mut impl BufRead, buf: &mut Vec<u8>) -> io::Result<bool> {
+    let mut msg_len = None;
+    loop {
+        buf.clear();
+        if r.read_until(b'\n', buf)? == 0 {
+            return Ok(false);
+        }
+        if buf == b"\r\n" {
+            break;
+        }
+        let l = str::from_utf8(buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if let Some(len) = l.strip_prefix("Content-Length: ") {
+            msg_len = Some(
+                len.trim_end()
+                    .parse()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            );
+        }
+    }
+
+    let msg_len = msg_len
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing Content-Length"))?;
+    buf.resize(msg_len, 0);
+    r.read_exact(buf)?;
+    Ok(true)
+}
+
+/// Appends the header of the next message to `buf`, up to and including the empty line ending it.
+/// Returns `Ok(false)` once the stream is closed.
+pub fn read_header(r: &mut impl BufRead, buf: &mut Vec<u8>) -> io::Result<bool> {
+    loop {
+        let start = buf.len();
+        if r.read_until(b'\n', buf)? == 0 {
+            return Ok(false);
+        }
+        if &buf[start..] == b"\r\n" {
+            return Ok(true);
+        }
+    }
+}
+
+/// Reads the client on a thread of its own, so a read that waits longer than `tick` fails with
+/// `Interrupted`. `read_exact` and `read_until` retry those, while the main loop uses them to check
+/// on the server when the client is quiet.
+pub struct Ticking {
+    chunks: Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    /// How much of `chunk` was read.
+    at: usize,
+    tick: Duration,
+}
+
+impl Ticking {
+    pub fn new(mut client: impl Read + Send + 'static, tick: Duration) -> Self {
+        let (tx, chunks) = channel();
+        thread::spawn(move || {
+            let mut buf = vec![0; 8 * 1024];
+            loop {
+                let chunk = match client.read(&mut buf) {
+                    // Dropping `tx` ends the stream.
+                    Ok(0) => break,
+                    Ok(n) => Ok(buf[..n].to_vec()),
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => Err(e),
+                };
+                let failed = chunk.is_err();
+                if tx.send(chunk).is_err() || failed {
+                    break;
+                }
+            }
+        });
+        Ticking {
+            chunks,
+            chunk: Vec::new(),
+            at: 0,
+            tick,
+        }
+    }
+}
+
+impl Read for Ticking {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.at == self.chunk.len() {
+            match self.chunks.recv_timeout(self.tick) {
+                Ok(chunk) => self.chunk = chunk?,
+                Err(RecvTimeoutError::Timeout) => return Err(io::ErrorKind::Interrupted.into()),
+                Err(RecvTimeoutError::Disconnected) => return Ok(0),
+            }
+            self.at = 0;
+        }
+        let n = buf.len().min(self.chunk.len() - self.at);
+        buf[..n].copy_from_slice(&self.chunk[self.at..self.at + n]);
+        self.at += n;
+        Ok(n)
+    }
+}
+
+pub fn write_msg(w: &mut impl Write, body: &[u8]) -> io::Result<()> {
+    write!(w, "Content-Length: {}\r\n\r\n", body.len())?;
+    w.write_all(body)?;
+    w.flush()
+}
+
+lazy_static! {
+    /// Both the server output thread and the proxy itself talk to the client,
+    /// so the header and body are written under one lock.
+    static ref CLIENT: Mutex<Box<dyn Write + Send>> = Mutex::new(Box::new(stdout()));
+    static ref TRACE: Mutex<Option<File>> = Mutex::new(None);
+}
+
+/// Replaces stdout as the connection to the client.
+pub fn set_client(client: Box<dyn Write + Send>) {
+    *CLIENT.lock().unwrap() = client;
+}
+
+pub fn send_client(body: &[u8]) -> io::Result<()> {
+    trace("to client", body);
+    write_msg(&mut *CLIENT.lock().unwrap(), body)
+}
+
+pub fn set_trace(file: File) {
+    *TRACE.lock().unwrap() = Some(file);
+}
+
+pub fn tracing() -> bool {
+    TRACE.lock().unwrap().is_some()
+}
+
+/// Appends `body` to the trace file, if there is one.
+/// Messages are traced where lsp-diff receives them and where it sends one it made or rewrote.
+pub fn trace(direction: &str, body: &[u8]) {
+    if let Some(ref mut file) = *TRACE.lock().unwrap() {
+        let _ = writeln!(file, "[{}] {}", direction, String::from_utf8_lossy(body));
+    }
+}
+
+pub fn show_message(typ: MessageType, message: String) {
+    let msg = serde_json::to_vec(&NotiS::new(Window::ShowMessage(ShowMessageParams {
+        typ,
+        message,
+    })))
+    .unwrap();
+    // If the client is gone there is no one left to tell.
+    let _ = send_client(&msg);
+}
+
+pub fn log_message(typ: MessageType, message: String) {
+    let msg = serde_json::to_vec(&NotiS::new(Window::LogMessage(LogMessageParams {
+        typ,
+        message,
+    })))
+    .unwrap();
+    let _ = send_client(&msg);
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+#[serde(tag = "method", content = "params")]
+pub enum Window {
+    #[serde(rename = "window/showMessage")]
+    ShowMessage(ShowMessageParams),
+    #[serde(rename = "window/logMessage")]
+    LogMessage(LogMessageParams),
+}
+
+/// Enough of a message to route it.
+/// Requests have both fields, notifications no `id` and responses no `method`.
+#[derive(Deserialize, Debug)]
+pub struct Envelope {
+    pub id: Option<Value>,
+    pub method: Option<String>,
+}
+
+/// The `Envelope` of a message from the start of its body, for bodies too big to buffer.
+/// Top level members are read in order up to the first value not wholly in `prefix`,
+/// so `method`, and `id` for requests, must come before `params`, the way clients write them.
+pub fn peek(prefix: &[u8]) -> Option<Envelope> {
+    let mut envelope = Envelope {
+        id: None,
+        method: None,
+    };
+    let mut rest = skip_ws(prefix);
+    if rest.first() != Some(&b'{') {
+        return None;
+    }
+    rest = &rest[1..];
+    while let Some((key, used)) = next_value::<String>(skip_ws(rest)) {
+        rest = skip_ws(&skip_ws(rest)[used..]);
+        if rest.first() != Some(&b':') {
+            return None;
+        }
+        rest = skip_ws(&rest[1..]);
+        let used = match key.as_str() {
+            "id" => next_value::<Value>(rest).map(|(id, used)| {
+                envelope.id = Some(id).filter(|id| !id.is_null());
+                used
+            }),
+            "method" => next_value::<String>(rest).map(|(method, used)| {
+                envelope.method = Some(method);
+                used
+            }),
+            _ => next_value::<IgnoredAny>(rest).map(|(_, used)| used),
+        };
+        match used {
+            Some(used) => rest = skip_ws(&rest[used..]),
+            None => break,
+        }
+        match rest.first() {
+            Some(b',') => rest = &rest[1..],
+            _ => break,
+        }
+    }
+    if envelope.method.is_some() {
+        Some(envelope)
+    } else {
+        None
+    }
+}
+
+/// The JSON value at the start of `s` and its length.
+/// A value running to the end of `s` may be cut short, so it counts as missing.
+fn next_value<T: DeserializeOwned>(s: &[u8]) -> Option<(T, usize)> {
+    let mut values = serde_json::Deserializer::from_slice(s).into_iter::<T>();
+    match values.next() {
+        Some(Ok(value)) if values.byte_offset() < s.len() => Some((value, values.byte_offset())),
+        _ => None,
+    }
+}
+
+fn skip_ws(s: &[u8]) -> &[u8] {
+    let start = s
+        .iter()
+        .position(|b| !b" \t\r\n".contains(b))
+        .unwrap_or(s.len());
+    &s[start..]
+}
+
+/// The document a message is about, if its params have a `textDocument`.
+pub fn document(body: &[u8]) -> Option<Url> {
+    #[derive(Deserialize)]
+    #[serde(rename_all = "camelCase")]
+    struct DocumentParams {
+        text_document: TextDocumentIdentifier,
+    }
+
+    serde_json::from_slice::<Params<DocumentParams>>(body)
+        .ok()
+        .map(|p| p.params.text_document.uri)
+}
+
+/// Just the params of a message whose method is already known.
+#[derive(Deserialize, Debug)]
+pub struct Params<P> {
+    pub params: P,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Resp<'a, R> {
+    jsonrpc: &'static str,
+    id: &'a Value,
+    result: R,
+}
+
+impl<'a, R> Resp<'a, R> {
+    pub fn new(id: &'a Value, result: R) -> Self {
+        Resp {
+            jsonrpc: "2.0",
+            id,
+            result,
+        }
+    }
+}
+
+pub const INVALID_PARAMS: i64 = -32602;
+pub const REQUEST_CANCELLED: i64 = -32800;
+pub const CONTENT_MODIFIED: i64 = -32801;
+
+#[derive(Serialize, Debug)]
+pub struct ResponseError {
+    pub code: i64,
+    pub message: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ErrResp<'a> {
+    jsonrpc: &'static str,
+    id: &'a Value,
+    error: ResponseError,
+}
+
+impl<'a> ErrResp<'a> {
+    pub fn new(id: &'a Value, code: i64, message: String) -> Self {
+        ErrResp {
+            jsonrpc: "2.0",
+            id,
+            error: ResponseError { code, message },
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Noti<M> {
+    /// Required, but only checked for.
+    #[allow(dead_code)]
+    pub jsonrpc: IgnoredAny,
+    #[serde(flatten)]
+    pub params: M,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
+pub struct NotiS<M> {
+    jsonrpc: &'static str,
+    #[serde(flatten)]
+    params: M,
+}
+
+impl<M> NotiS<M> {
+    pub fn new(params: M) -> Self {
+        NotiS {
+            jsonrpc: "2.0",
+            params,
+        }
+    }
+}
+
+/// A request sent by lsp-diff itself.
+#[derive(Serialize, Debug)]
+pub struct ReqS<M> {
+    jsonrpc: &'static str,
+    id: &'static str,
+    #[serde(flatten)]
+    params: M,
+}
+
+impl<M> ReqS<M> {
+    pub fn new(id: &'static str, params: M) -> Self {
+        ReqS {
+            jsonrpc: "2.0",
+            id,
+            params,
+        }
+    }
+}
+
+#[test]
+fn peek_test() {
+    let body = br#"{"jsonrpc":"2.0","id":7,"method":"textDocument/didOpen","params":{"textDocument":{"text":"fn"#;
+    let envelope = peek(body).unwrap();
+    assert_eq!(envelope.id, Some(Value::from(7)));
+    assert_eq!(envelope.method.as_deref(), Some("textDocument/didOpen"));
+    assert!(peek(br#"{"jsonrpc":"2.0","id":12"#).is_none());
+}
+
+#[cfg(unix)]
+#[test]
+fn ticking_test() {
+    let (mut client, proxy) = std::os::unix::net::UnixStream::pair().unwrap();
+    let mut r = Ticking::new(proxy, Duration::from_millis(10));
+    let mut buf = [0; 3];
+    assert_eq!(
+        r.read(&mut buf).unwrap_err().kind(),
+        io::ErrorKind::Interrupted
+    );
+    client.write_all(b"abc").unwrap();
+    r.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"abc");
+    drop(client);
+    assert_eq!(r.read(&mut buf).unwrap(), 0);
+}
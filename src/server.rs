@@ -0,0 +1,451 @@
+use std::collections::{HashMap, VecDeque};
+use std::env;
+use std::io::{self, BufReader};
+use std::process::{self, Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use lsp_types::*;
+use serde::Serialize;
+
+use crate::cli::Args;
+use crate::commands::{self, SaveText};
+use crate::document::{Documents, Snapshots};
+use crate::format;
+use crate::limits::Limits;
+use crate::notebook::{NotebookDid, NotebookOpened};
+use crate::pending::{Pending, Request};
+use crate::rpc::{self, Envelope, NotiS, ReqS};
+use crate::stats;
+use crate::stderr_log::StderrLog;
+use crate::tokens::{self, Tokens};
+use crate::workspace_edit;
+use crate::{Did, Init};
+
+/// Id of the `initialize` request sent to a restarted server.
+/// The client already has an `InitializeResult`, so the response is swallowed.
+pub const RESTART_INIT_ID: &str = "lsp-diff/initialize";
+
+/// How long a restarted server gets to answer `initialize`.
+const INIT_TIMEOUT: Duration = Duration::from_secs(30);
+
+pub struct Server {
+    /// Shared with the workers, which send split changes themselves.
+    pub stdin: Arc<Input>,
+    child: Child,
+    limits: Limits,
+    /// Fires when the response to `RESTART_INIT_ID` arrives.
+    initialized: Receiver<()>,
+    /// When the server last wrote a message.
+    last_output: Arc<Mutex<Instant>>,
+}
+
+impl Server {
+    fn spawn(supervisor: &Supervisor) -> io::Result<Server> {
+        let Supervisor {
+            ref command,
+            ref env,
+            ref limits,
+            ref stderr,
+            ..
+        } = *supervisor;
+        let mut cmd = Command::new(&command[0]);
+        cmd.args(&command[1..])
+            .envs(env)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        if let Err(e) = limits.apply(&mut cmd) {
+            rpc::show_message(
+                MessageType::Warning,
+                format!(
+                    "lsp-diff could not set up the cgroup of {}: {}",
+                    command[0], e
+                ),
+            );
+        }
+        let mut child = cmd.spawn()?;
+        limits.spawned(child.id());
+
+        let stdin = child.stdin.take().expect("server stdin failed");
+        let stdout = child.stdout.take().expect("server stdout failed");
+        stderr.capture(child.stderr.take().expect("server stderr failed"));
+        let (tx, initialized) = channel();
+        let last_output = Arc::new(Mutex::new(Instant::now()));
+        let output = last_output.clone();
+        let pending = supervisor.pending.clone();
+        let snapshots = supervisor.snapshots.clone();
+        let tokens = supervisor.tokens.clone();
+        let save_text = supervisor.save_text.clone();
+        thread::spawn(move || {
+            forward_output(stdout, tx, pending, snapshots, tokens, save_text, output)
+        });
+
+        Ok(Server {
+            stdin: Arc::new(Input {
+                pipe: Mutex::new(stdin),
+            }),
+            child,
+            limits: limits.clone(),
+            initialized,
+            last_output,
+        })
+    }
+
+    /// Whether the server exited. It isn't reaped yet, so its pid and process group id can't be
+    /// reused by another process before `kill` signaled the group.
+    #[cfg(unix)]
+    pub fn has_exited(&mut self) -> bool {
+        let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+        let flags = libc::WEXITED | libc::WNOHANG | libc::WNOWAIT;
+        let id = self.child.id() as libc::id_t;
+        // Nothing is written to `info` while the child runs.
+        unsafe { libc::waitid(libc::P_PID, id, &mut info, flags) != 0 || info.si_signo != 0 }
+    }
+
+    #[cfg(not(unix))]
+    pub fn has_exited(&mut self) -> bool {
+        match self.child.try_wait() {
+            Ok(None) => false,
+            _ => true,
+        }
+    }
+
+    pub fn silent_for(&self) -> Duration {
+        self.last_output.lock().unwrap().elapsed()
+    }
+
+    /// Kills the server along with its process group.
+    pub fn kill(&mut self) {
+        kill(&mut self.child, &self.limits);
+    }
+
+    /// Closes the server's stdin and gives it `timeout` to exit on its own before killing it.
+    pub fn wait_or_kill(self, timeout: Duration) {
+        let Server {
+            stdin,
+            mut child,
+            limits,
+            ..
+        } = self;
+        drop(stdin);
+
+        let start = Instant::now();
+        while start.elapsed() < timeout {
+            match child.try_wait() {
+                Ok(None) => thread::sleep(Duration::from_millis(20)),
+                _ => return limits.reaped(),
+            }
+        }
+        kill(&mut child, &limits);
+    }
+
+    pub fn send<M: Serialize>(&mut self, msg: &M) -> io::Result<()> {
+        self.stdin.send(msg)
+    }
+}
+
+/// The server's stdin. A message is written under one lock, so messages from several threads don't
+/// interleave.
+pub struct Input {
+    pipe: Mutex<ChildStdin>,
+}
+
+impl Input {
+    pub fn lock(&self) -> MutexGuard<'_, ChildStdin> {
+        self.pipe.lock().unwrap()
+    }
+
+    pub fn send<M: Serialize>(&self, msg: &M) -> io::Result<()> {
+        let body = serde_json::to_vec(msg)?;
+        rpc::trace("to server", &body);
+        rpc::write_msg(&mut *self.lock(), &body)
+    }
+}
+
+/// Kills `child` and its process group. `child` mustn't be reaped yet, or its pid may already
+/// belong to another process.
+fn kill(child: &mut Child, limits: &Limits) {
+    #[cfg(unix)]
+    limits.signal(child.id(), libc::SIGKILL);
+    #[cfg(not(unix))]
+    let _ = child.kill();
+    // Reap it, so it doesn't linger as a zombie.
+    let _ = child.wait();
+    limits.reaped();
+}
+
+/// Copies server messages to the client until the server closes stdout.
+fn forward_output(
+    stdout: ChildStdout,
+    initialized: Sender<()>,
+    pending: Pending,
+    snapshots: Snapshots,
+    tokens: Tokens,
+    save_text: SaveText,
+    last_output: Arc<Mutex<Instant>>,
+) {
+    let mut stdout = BufReader::new(stdout);
+    let mut buf = Vec::with_capacity(5000);
+    loop {
+        match rpc::read_msg(&mut stdout, &mut buf) {
+            Ok(true) => (),
+            // Only this message is lost, the next header starts afresh.
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {
+                log::warn!("Dropped a message from the server: {}", e);
+                continue;
+            }
+            _ => break,
+        }
+        *last_output.lock().unwrap() = Instant::now();
+        rpc::trace("from server", &buf);
+        let rewritten = match serde_json::from_slice(&buf) {
+            Ok(Envelope {
+                id: Some(id),
+                method: None,
+            }) => {
+                if id == RESTART_INIT_ID {
+                    let _ = initialized.send(());
+                    continue;
+                }
+                match pending.remove(&id) {
+                    // Already answered with an error during a restart.
+                    None => continue,
+                    Some(ref req) if req.method == "initialize" => {
+                        let resp = tokens.advertise(&buf);
+                        commands::advertise(resp.as_ref().unwrap_or(&buf), &save_text).or(resp)
+                    }
+                    Some(Request {
+                        document: Some(ref doc),
+                        ..
+                    }) => format::minimize(&buf, doc),
+                    Some(ref req)
+                        if workspace_edit::CODE_ACTION_METHODS.contains(&req.method.as_str()) =>
+                    {
+                        workspace_edit::minimize(&buf, &req.method, &snapshots)
+                    }
+                    Some(ref req) if tokens::METHODS.contains(&req.method.as_str()) => {
+                        tokens.respond(&req.id, &buf)
+                    }
+                    _ => None,
+                }
+            }
+            Ok(Envelope {
+                method: Some(ref method),
+                ..
+            }) if method == workspace_edit::APPLY_EDIT => {
+                workspace_edit::minimize(&buf, method, &snapshots)
+            }
+            _ => None,
+        };
+        if let Some(msg) = rewritten {
+            buf = msg;
+        }
+        if rpc::send_client(&buf).is_err() {
+            break;
+        }
+    }
+}
+
+/// Starts the server and brings it back after crashes.
+pub struct Supervisor {
+    command: Vec<String>,
+    env: HashMap<String, String>,
+    /// When recent crashes happened, oldest first.
+    crashes: VecDeque<Instant>,
+    /// Give up after this many crashes within `crash_window`.
+    pub max_crashes: usize,
+    pub crash_window: Duration,
+    /// Delay before the first restart, doubled for each further crash in the window.
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+    pub pending: Pending,
+    /// The client's documents, which edits from the server are diffed against.
+    pub snapshots: Snapshots,
+    /// Semantic tokens sent to the client, for servers without deltas.
+    pub tokens: Tokens,
+    pub save_text: SaveText,
+    /// Re-send idempotent requests to a restarted server instead of failing them.
+    pub reissue: bool,
+    /// The server is considered hung once it has been silent this long
+    /// while a request has been waiting this long.
+    pub hang_timeout: Duration,
+    pub limits: Limits,
+    pub stderr: StderrLog,
+}
+
+impl Supervisor {
+    pub fn new(args: &Args) -> Self {
+        let stderr_log = args.stderr_log.clone().unwrap_or_else(|| {
+            env::temp_dir().join(format!("lsp-diff-{}-stderr.log", process::id()))
+        });
+        Supervisor {
+            command: args.command.clone(),
+            env: args.env.clone(),
+            crashes: VecDeque::new(),
+            max_crashes: args.max_crashes,
+            crash_window: args.crash_window,
+            backoff: Duration::from_millis(250),
+            max_backoff: Duration::from_secs(30),
+            pending: Pending::default(),
+            snapshots: Snapshots::default(),
+            tokens: Tokens::default(),
+            save_text: SaveText::default(),
+            reissue: args.reissue,
+            hang_timeout: args.hang_timeout,
+            limits: args.limits.clone(),
+            stderr: StderrLog::new(stderr_log, 10 * 1024 * 1024, 2),
+        }
+    }
+
+    pub fn spawn(&self) -> io::Result<Server> {
+        Server::spawn(self)
+    }
+
+    pub fn is_hung(&self, server: &Server) -> bool {
+        server.silent_for() > self.hang_timeout
+            && self
+                .pending
+                .oldest()
+                .is_some_and(|waited| waited > self.hang_timeout)
+    }
+
+    /// Restarts a server that died or hung, waiting out the backoff first.
+    /// `what` says what happened to it, e.g. "crashed".
+    /// Errs once this happened `max_crashes` times within `crash_window`,
+    /// after telling the client.
+    pub fn crashed(
+        &mut self,
+        server: &mut Server,
+        docs: &Documents,
+        init: &InitializeParams,
+        what: &str,
+    ) -> Result<(), ()> {
+        loop {
+            let now = Instant::now();
+            while self
+                .crashes
+                .front()
+                .is_some_and(|&t| now.duration_since(t) > self.crash_window)
+            {
+                self.crashes.pop_front();
+            }
+            self.crashes.push_back(now);
+
+            if self.crashes.len() > self.max_crashes {
+                self.report_stderr(what);
+                rpc::show_message(
+                    MessageType::Error,
+                    format!(
+                        "{} {} {} times in {}s, lsp-diff gave up restarting it.",
+                        self.command[0],
+                        what,
+                        self.crashes.len(),
+                        self.crash_window.as_secs()
+                    ),
+                );
+                return Err(());
+            }
+
+            let backoff = self.backoff_for(self.crashes.len());
+            log::warn!("{} {}, restarting in {:?}", self.command[0], what, backoff);
+            thread::sleep(backoff);
+            // After the backoff, so the stderr thread has caught up with the dying server.
+            self.report_stderr(what);
+
+            if self.restart(server, docs, init).is_ok() {
+                rpc::show_message(
+                    MessageType::Warning,
+                    format!(
+                        "{} {} and was restarted by lsp-diff.",
+                        self.command[0], what
+                    ),
+                );
+                return Ok(());
+            }
+        }
+    }
+
+    /// Sends the server's last stderr lines to the client, they usually say why it died.
+    fn report_stderr(&self, what: &str) {
+        let lines = self.stderr.take_recent();
+        if !lines.is_empty() {
+            rpc::log_message(
+                MessageType::Error,
+                format!(
+                    "{} {}, its last stderr output was:\n{}",
+                    self.command[0],
+                    what,
+                    lines.join("\n")
+                ),
+            );
+        }
+    }
+
+    fn backoff_for(&self, crashes: usize) -> Duration {
+        let doublings = (crashes.max(1) - 1).min(16) as u32;
+        (self.backoff * 2u32.pow(doublings)).min(self.max_backoff)
+    }
+
+    /// Replaces `server` with a fresh one and replays the client's session:
+    /// `initialize`, `initialized` and a `didOpen` for every open document and notebook.
+    /// Requests the old server never answered are failed or re-issued.
+    pub fn restart(
+        &mut self,
+        server: &mut Server,
+        docs: &Documents,
+        init: &InitializeParams,
+    ) -> io::Result<()> {
+        stats::record(|s| s.restarts += 1);
+        server.kill();
+        *server = self.spawn()?;
+
+        server.send(&ReqS::new(RESTART_INIT_ID, Init::Init(init.clone())))?;
+        // Nothing else may be sent before the server answers `initialize`.
+        server
+            .initialized
+            .recv_timeout(INIT_TIMEOUT)
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "initialize timed out"))?;
+        server.send(&NotiS::new(Init::Initialized(InitializedParams {})))?;
+
+        for text_document in docs.items() {
+            server.send(&NotiS::new(Did::Open(DidOpenTextDocumentParams {
+                text_document,
+            })))?;
+        }
+        for notebook in docs.notebooks() {
+            // Cells whose text is unknown can't be replayed, the server goes without them.
+            let mut notebook = notebook.clone();
+            let mut cell_text_documents = Vec::new();
+            notebook
+                .cells
+                .retain(|cell| match docs.item(&cell.document) {
+                    Some(item) => {
+                        cell_text_documents.push(item);
+                        true
+                    }
+                    None => false,
+                });
+            server.send(&NotiS::new(NotebookDid::Open(NotebookOpened {
+                notebook_document: &notebook,
+                cell_text_documents,
+            })))?;
+        }
+        self.tokens.forget_requests();
+        self.pending.fail_or_reissue(server, self.reissue)
+    }
+}
+
+#[test]
+fn backoff_test() {
+    let s = Supervisor::new(&Args {
+        command: vec!["true".to_owned()],
+        stderr_log: Some(env::temp_dir().join("lsp-diff-backoff-test.log")),
+        ..Args::default()
+    });
+    assert_eq!(s.backoff_for(1), Duration::from_millis(250));
+    assert_eq!(s.backoff_for(3), Duration::from_millis(1000));
+    assert_eq!(s.backoff_for(100), Duration::from_secs(30));
+}
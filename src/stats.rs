@@ -0,0 +1,99 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
+use serde::Serialize;
+
+/// Counters answering whether splitting pays off.
+/// Sent as the result of a `lsp-diff/stats` request and logged on exit.
+#[derive(Serialize, Debug, Default, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Stats {
+    /// Content changes the client sent.
+    pub changes_received: u64,
+    /// Content changes sent to the server in their place.
+    pub changes_emitted: u64,
+    /// Text bytes received minus text bytes emitted.
+    pub bytes_saved: i64,
+    /// Microseconds spent splitting ranged changes.
+    pub incremental: Histogram,
+    /// Microseconds spent diffing full document changes.
+    pub full: Histogram,
+    /// Changes and edits forwarded as they were because a diff engine failed on them.
+    pub fallbacks: u64,
+    pub restarts: u64,
+    /// Bytes of document text and line hashes lsp-diff holds.
+    pub documents_bytes: u64,
+    /// Documents whose text was moved to a temp file to stay under the store limit.
+    pub documents_dropped: u64,
+    /// Saves whose text differed from lsp-diff's copy, which was then resynced.
+    pub saves_diverged: u64,
+    /// Percent of memory plus swap free, sampled every 10s.
+    pub free_memory: Histogram,
+}
+
+/// Power of two buckets, `buckets[i]` counts the values `v` with `2^(i-1) <= v < 2^i`.
+#[derive(Serialize, Debug, Default, Clone)]
+pub struct Histogram {
+    pub count: u64,
+    pub sum: u64,
+    pub min: u64,
+    pub max: u64,
+    pub buckets: Vec<u64>,
+}
+
+impl Histogram {
+    pub fn record(&mut self, v: u64) {
+        let i = (64 - v.leading_zeros()) as usize;
+        if self.buckets.len() <= i {
+            self.buckets.resize(i + 1, 0);
+        }
+        self.buckets[i] += 1;
+
+        self.min = if self.count == 0 { v } else { self.min.min(v) };
+        self.max = self.max.max(v);
+        self.count += 1;
+        self.sum += v;
+    }
+}
+
+lazy_static! {
+    static ref STATS: Mutex<Stats> = Mutex::new(Stats::default());
+}
+
+/// Updates the stats under their lock.
+pub fn record(f: impl FnOnce(&mut Stats)) {
+    f(&mut STATS.lock().unwrap())
+}
+
+/// Runs `f`, recording how long it took in the histogram chosen by `histogram`.
+pub fn time<R>(histogram: fn(&mut Stats) -> &mut Histogram, f: impl FnOnce() -> R) -> R {
+    let start = Instant::now();
+    let r = f();
+    let micros = micros(start.elapsed());
+    record(|s| histogram(s).record(micros));
+    r
+}
+
+pub fn snapshot() -> Stats {
+    STATS.lock().unwrap().clone()
+}
+
+/// Logs the stats at info level, `--log-level warn,lsp_diff::stats=info` shows just them.
+pub fn log() {
+    log::info!("stats: {}", serde_json::to_string(&snapshot()).unwrap());
+}
+
+fn micros(d: Duration) -> u64 {
+    d.as_secs() * 1_000_000 + u64::from(d.subsec_micros())
+}
+
+#[test]
+fn histogram_test() {
+    let mut h = Histogram::default();
+    for &v in &[0, 1, 3, 2, 900] {
+        h.record(v);
+    }
+    assert_eq!(h.buckets, vec![1, 1, 2, 0, 0, 0, 0, 0, 0, 0, 1]);
+    assert_eq!((h.count, h.sum, h.min, h.max), (5, 906, 0, 900));
+}
@@ -0,0 +1,149 @@
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::process::ChildStderr;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// How many of the latest stderr lines are kept for crash reports.
+const RECENT_LINES: usize = 50;
+
+/// The servers' stderr for this session, written to a size-rotated file.
+/// Shared by every server generation, so restarts append to the same log.
+#[derive(Clone)]
+pub struct StderrLog(Arc<Mutex<Inner>>);
+
+struct Inner {
+    path: PathBuf,
+    /// `None` if the log couldn't be opened, lines are then only kept in `recent`.
+    file: Option<File>,
+    written: u64,
+    /// Rotate once the log grows past this many bytes.
+    max_size: u64,
+    /// Rotated logs to keep, as `path.1` (newest) to `path.keep`.
+    keep: usize,
+    /// Lines of the current server only, so a crash report doesn't quote an earlier one.
+    recent: VecDeque<String>,
+    /// Counts the servers captured, a dying server's last lines are left out of `recent`
+    /// once its successor started.
+    generation: u64,
+}
+
+impl StderrLog {
+    pub fn new(path: PathBuf, max_size: u64, keep: usize) -> Self {
+        StderrLog(Arc::new(Mutex::new(Inner {
+            file: open(&path),
+            path,
+            written: 0,
+            max_size,
+            keep,
+            recent: VecDeque::with_capacity(RECENT_LINES),
+            generation: 0,
+        })))
+    }
+
+    /// Copies `stderr` of a freshly spawned server into the log until the server closes it.
+    pub fn capture(&self, stderr: ChildStderr) {
+        let log = self.0.clone();
+        let generation = {
+            let mut inner = log.lock().unwrap();
+            inner.generation += 1;
+            inner.recent.clear();
+            inner.generation
+        };
+        thread::spawn(move || {
+            let mut stderr = BufReader::new(stderr);
+            let mut line = Vec::new();
+            while let Ok(n) = stderr.read_until(b'\n', &mut line) {
+                if n == 0 {
+                    break;
+                }
+                log.lock().unwrap().write(&line, generation);
+                line.clear();
+            }
+        });
+    }
+
+    /// Takes the current server's lines written since the last call, at most `RECENT_LINES`.
+    pub fn take_recent(&self) -> Vec<String> {
+        self.0.lock().unwrap().recent.drain(..).collect()
+    }
+}
+
+impl Inner {
+    fn write(&mut self, line: &[u8], generation: u64) {
+        if self.written + line.len() as u64 > self.max_size {
+            self.rotate();
+        }
+        if let Some(ref mut file) = self.file {
+            if file.write_all(line).is_ok() {
+                self.written += line.len() as u64;
+            }
+        }
+
+        if generation != self.generation {
+            return;
+        }
+        if self.recent.len() == RECENT_LINES {
+            self.recent.pop_front();
+        }
+        self.recent
+            .push_back(String::from_utf8_lossy(line).trim_end().to_owned());
+    }
+
+    fn rotate(&mut self) {
+        self.file = None;
+        for i in (1..self.keep).rev() {
+            let _ = fs::rename(numbered(&self.path, i), numbered(&self.path, i + 1));
+        }
+        if self.keep > 0 {
+            let _ = fs::rename(&self.path, numbered(&self.path, 1));
+        }
+        self.file = open(&self.path);
+        self.written = 0;
+    }
+}
+
+fn open(path: &Path) -> Option<File> {
+    OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(path)
+        .ok()
+}
+
+fn numbered(path: &Path, i: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", i));
+    PathBuf::from(name)
+}
+
+#[test]
+fn rotate_test() {
+    let dir = std::env::temp_dir().join(format!("lsp-diff-rotate-test-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("stderr.log");
+    let log = StderrLog::new(path.clone(), 16, 2);
+    for line in &["aaaaaaaa\n", "bbbbbbbb\n", "cccccccc\n"] {
+        log.0.lock().unwrap().write(line.as_bytes(), 0);
+    }
+    // Nothing is cut off a log, it's rotated before it grows past 16 bytes.
+    assert_eq!(fs::read_to_string(&path).unwrap(), "cccccccc\n");
+    assert_eq!(
+        fs::read_to_string(numbered(&path, 1)).unwrap(),
+        "bbbbbbbb\n"
+    );
+    assert_eq!(
+        fs::read_to_string(numbered(&path, 2)).unwrap(),
+        "aaaaaaaa\n"
+    );
+    assert_eq!(log.take_recent(), vec!["aaaaaaaa", "bbbbbbbb", "cccccccc"]);
+
+    // A previous server's lines stay out of the next crash report.
+    log.0.lock().unwrap().generation += 1;
+    log.0.lock().unwrap().write(b"late\n", 0);
+    assert!(log.take_recent().is_empty());
+    fs::remove_dir_all(&dir).unwrap();
+}
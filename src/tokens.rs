@@ -0,0 +1,282 @@
+//! Semantic token deltas for servers that only send full token arrays.
+//! lsp-diff keeps the last array it sent for each document and answers
+//! `semanticTokens/full/delta` with the difference to a fresh full array.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use diffs::{myers, Diff, Replace};
+use lsp_types::{TextDocumentIdentifier, Url};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+use crate::rpc;
+
+pub const FULL: &str = "textDocument/semanticTokens/full";
+pub const FULL_DELTA: &str = "textDocument/semanticTokens/full/delta";
+pub const METHODS: &[&str] = &[FULL, FULL_DELTA];
+
+/// Integers per token in the token array.
+const TOKEN_LEN: usize = 5;
+
+/// Shared with the thread forwarding server output, which caches the responses.
+#[derive(Clone, Default)]
+pub struct Tokens(Arc<Mutex<State>>);
+
+#[derive(Default)]
+struct State {
+    /// The server has no deltas, lsp-diff makes them.
+    synthesize: bool,
+    /// The token array last sent to the client for each document.
+    sent: HashMap<Url, Sent>,
+    /// Token requests waiting for their response, by id.
+    requests: HashMap<String, Requested>,
+    result_ids: u64,
+}
+
+struct Sent {
+    result_id: String,
+    data: Vec<u32>,
+}
+
+struct Requested {
+    uri: Url,
+    /// The result id a delta is asked against.
+    previous_result_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Request {
+    id: Value,
+    params: Params,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Params {
+    text_document: TextDocumentIdentifier,
+    previous_result_id: Option<String>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct Edit {
+    start: usize,
+    delete_count: usize,
+    data: Vec<u32>,
+}
+
+impl Tokens {
+    /// Has the server's `InitializeResult` offer deltas if the server only does full arrays.
+    pub fn advertise(&self, resp: &[u8]) -> Option<Vec<u8>> {
+        let mut resp: Value = serde_json::from_slice(resp).ok()?;
+        {
+            let full = resp
+                .get_mut("result")?
+                .get_mut("capabilities")?
+                .get_mut("semanticTokensProvider")?
+                .get_mut("full")?;
+            match *full {
+                Value::Bool(true) => (),
+                Value::Object(ref full) if full.get("delta") != Some(&Value::Bool(true)) => (),
+                _ => return None,
+            }
+            *full = json!({ "delta": true });
+        }
+        self.0.lock().unwrap().synthesize = true;
+        serde_json::to_vec(&resp).ok()
+    }
+
+    /// Notes a token request from the client, so its response can be cached.
+    /// A delta request is turned into a full one, which is returned with its header
+    /// to be sent instead.
+    pub fn request(&self, method: &str, body: &[u8]) -> Option<Vec<u8>> {
+        let mut state = self.0.lock().unwrap();
+        if !state.synthesize {
+            return None;
+        }
+        let Request { id, params } = serde_json::from_slice(body).ok()?;
+        let full = if method == FULL_DELTA {
+            let full = json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "method": FULL,
+                "params": { "textDocument": params.text_document },
+            });
+            let full = serde_json::to_vec(&full).ok()?;
+            rpc::trace("to server", &full);
+            let mut msg = Vec::with_capacity(full.len() + 30);
+            rpc::write_msg(&mut msg, &full).ok()?;
+            Some(msg)
+        } else {
+            None
+        };
+        state.requests.insert(
+            id.to_string(),
+            Requested {
+                uri: params.text_document.uri,
+                previous_result_id: params.previous_result_id,
+            },
+        );
+        full
+    }
+
+    /// Caches the token array of a response to a request passed to `request`,
+    /// and turns it into a delta if the client asked for one against the array cached before.
+    pub fn respond(&self, id: &Value, resp: &[u8]) -> Option<Vec<u8>> {
+        let mut state = self.0.lock().unwrap();
+        let requested = state.requests.remove(&id.to_string())?;
+        let mut resp: Value = serde_json::from_slice(resp).ok()?;
+        let data: Option<Vec<u32>> = resp
+            .get("result")
+            .and_then(|result| result.get("data"))
+            .and_then(|data| serde_json::from_value(data.clone()).ok());
+        let data = match data {
+            Some(data) => data,
+            // The client's tokens are unknown now.
+            None => {
+                state.sent.remove(&requested.uri);
+                return None;
+            }
+        };
+
+        state.result_ids += 1;
+        let result_id = format!("lsp-diff/{}", state.result_ids);
+        let result = match (state.sent.get(&requested.uri), requested.previous_result_id) {
+            (Some(sent), Some(previous)) if sent.result_id == previous => json!({
+                "resultId": result_id,
+                "edits": edits(&sent.data, &data),
+            }),
+            _ => json!({ "resultId": result_id, "data": data }),
+        };
+        state.sent.insert(requested.uri, Sent { result_id, data });
+        resp["result"] = result;
+        serde_json::to_vec(&resp).ok()
+    }
+
+    pub fn forget(&self, uri: &Url) {
+        self.0.lock().unwrap().sent.remove(uri);
+    }
+
+    /// Forgets the requests waiting for a response from a server that is gone.
+    /// Token requests aren't idempotent, so a restart fails rather than re-issues them.
+    pub fn forget_requests(&self) {
+        self.0.lock().unwrap().requests.clear();
+    }
+}
+
+/// Edits turning the token array `old` into `new`, diffed token by token.
+fn edits(old: &[u32], new: &[u32]) -> Vec<Edit> {
+    let old: Vec<_> = old.chunks(TOKEN_LEN).collect();
+    let new: Vec<_> = new.chunks(TOKEN_LEN).collect();
+    let mut edits = Edits {
+        edits: Vec::new(),
+        new: &new,
+    };
+    myers::diff(
+        &mut Replace::new(&mut edits),
+        &old,
+        0,
+        old.len(),
+        &new,
+        0,
+        new.len(),
+    )
+    .unwrap();
+    edits.edits
+}
+
+struct Edits<'n> {
+    edits: Vec<Edit>,
+    new: &'n [&'n [u32]],
+}
+
+impl<'n> Diff for Edits<'n> {
+    type Error = ();
+    fn delete(&mut self, old: usize, len: usize) -> Result<(), Self::Error> {
+        self.replace(old, len, 0, 0)
+    }
+
+    fn insert(&mut self, old: usize, new: usize, new_len: usize) -> Result<(), Self::Error> {
+        self.replace(old, 0, new, new_len)
+    }
+
+    fn replace(
+        &mut self,
+        old: usize,
+        old_len: usize,
+        new: usize,
+        new_len: usize,
+    ) -> Result<(), Self::Error> {
+        self.edits.push(Edit {
+            start: old * TOKEN_LEN,
+            delete_count: old_len * TOKEN_LEN,
+            data: self.new[new..new + new_len].concat(),
+        });
+        Ok(())
+    }
+}
+
+#[test]
+fn edits_test() {
+    let old = [0, 0, 2, 1, 0, 1, 4, 3, 2, 0, 0, 5, 1, 1, 0];
+    let new = [0, 0, 2, 1, 0, 1, 4, 6, 2, 0, 0, 5, 1, 1, 0];
+    assert_eq!(
+        edits(&old, &new),
+        vec![Edit {
+            start: 5,
+            delete_count: 5,
+            data: vec![1, 4, 6, 2, 0],
+        }]
+    );
+    assert!(edits(&new, &new).is_empty());
+}
+
+#[test]
+fn delta_test() {
+    let tokens = Tokens::default();
+    let init = br#"{"jsonrpc":"2.0","id":0,"result":{"capabilities":{"semanticTokensProvider":{"full":true}}}}"#;
+    let init: Value = serde_json::from_slice(&tokens.advertise(init).unwrap()).unwrap();
+    assert_eq!(
+        init["result"]["capabilities"]["semanticTokensProvider"]["full"],
+        json!({ "delta": true })
+    );
+
+    let uri = "file:///a.rs";
+    let resp = |id: u32, data: &[u32]| {
+        let resp = json!({ "jsonrpc": "2.0", "id": id, "result": { "data": data } });
+        let resp = tokens.respond(&json!(id), &serde_json::to_vec(&resp).unwrap());
+        serde_json::from_slice::<Value>(&resp.unwrap()).unwrap()["result"].clone()
+    };
+
+    let full = json!({ "id": 1, "params": { "textDocument": { "uri": uri } } });
+    assert!(tokens
+        .request(FULL, &serde_json::to_vec(&full).unwrap())
+        .is_none());
+    let result = resp(1, &[0, 0, 2, 1, 0]);
+    assert_eq!(result["data"], json!([0, 0, 2, 1, 0]));
+
+    let delta = json!({ "id": 2, "params": {
+        "textDocument": { "uri": uri },
+        "previousResultId": result["resultId"],
+    } });
+    let msg = tokens
+        .request(FULL_DELTA, &serde_json::to_vec(&delta).unwrap())
+        .unwrap();
+    let body = msg.windows(4).position(|w| w == b"\r\n\r\n").unwrap() + 4;
+    let sent: Value = serde_json::from_slice(&msg[body..]).unwrap();
+    assert_eq!(sent["method"], FULL);
+    assert_eq!(sent["id"], 2);
+
+    let result = resp(2, &[0, 0, 2, 1, 0, 1, 0, 3, 2, 0]);
+    assert!(result.get("data").is_none());
+    assert_eq!(
+        result["edits"],
+        json!([{ "start": 5, "deleteCount": 0, "data": [1, 0, 3, 2, 0] }])
+    );
+
+    let full = json!({ "id": 3, "params": { "textDocument": { "uri": uri } } });
+    tokens.request(FULL, &serde_json::to_vec(&full).unwrap());
+    tokens.forget_requests();
+    assert!(tokens.respond(&json!(3), b"{}").is_none());
+}
@@ -0,0 +1,102 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+use lsp_types::Url;
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Threads that split changes and send them to the server.
+/// Jobs for the same document always run on the same thread, so they run in the order given.
+pub struct Workers {
+    queues: Vec<Sender<Job>>,
+    queued: Arc<Queued>,
+}
+
+/// How many jobs each thread has yet to finish.
+struct Queued {
+    counts: Mutex<Vec<usize>>,
+    done: Condvar,
+}
+
+impl Workers {
+    /// With no threads every job runs right away on the caller's thread.
+    pub fn new(threads: usize) -> Self {
+        let queued = Arc::new(Queued {
+            counts: Mutex::new(vec![0; threads]),
+            done: Condvar::new(),
+        });
+        let queues = (0..threads)
+            .map(|i| {
+                let (tx, rx) = channel::<Job>();
+                let queued = queued.clone();
+                thread::Builder::new()
+                    .name(format!("lsp-diff worker {}", i))
+                    .spawn(move || {
+                        for job in rx {
+                            if panic::catch_unwind(AssertUnwindSafe(job)).is_err() {
+                                log::error!("A job on worker {} panicked", i);
+                            }
+                            queued.counts.lock().unwrap()[i] -= 1;
+                            queued.done.notify_all();
+                        }
+                    })
+                    .expect("failed to spawn a worker");
+                tx
+            })
+            .collect();
+        Workers { queues, queued }
+    }
+
+    /// Runs `job` after the jobs already queued for `uri`.
+    pub fn run(&self, uri: &Url, job: impl FnOnce() + Send + 'static) {
+        if self.queues.is_empty() {
+            return job();
+        }
+        let i = self.index(uri);
+        self.queued.counts.lock().unwrap()[i] += 1;
+        // Workers only stop when `Workers` is dropped.
+        self.queues[i].send(Box::new(job)).unwrap();
+    }
+
+    /// Whether jobs for `uri` may still be queued or running.
+    /// Messages about `uri` have to wait their turn behind them.
+    pub fn pending(&self, uri: &Url) -> bool {
+        !self.queues.is_empty() && self.queued.counts.lock().unwrap()[self.index(uri)] > 0
+    }
+
+    pub fn busy(&self) -> bool {
+        self.queued.counts.lock().unwrap().iter().any(|&n| n > 0)
+    }
+
+    /// Blocks until every queued job has finished.
+    pub fn wait(&self) {
+        let mut counts = self.queued.counts.lock().unwrap();
+        while counts.iter().any(|&n| n > 0) {
+            counts = self.queued.done.wait(counts).unwrap();
+        }
+    }
+
+    fn index(&self, uri: &Url) -> usize {
+        let mut hasher = DefaultHasher::new();
+        uri.hash(&mut hasher);
+        hasher.finish() as usize % self.queues.len()
+    }
+}
+
+#[test]
+fn order_test() {
+    let workers = Workers::new(3);
+    let log = Arc::new(Mutex::new(Vec::new()));
+    let uri = Url::parse("file:///a.rs").unwrap();
+    for n in 0..100 {
+        let log = log.clone();
+        workers.run(&uri, move || log.lock().unwrap().push(n));
+    }
+    workers.wait();
+    assert!(!workers.pending(&uri));
+    assert_eq!(*log.lock().unwrap(), (0..100).collect::<Vec<_>>());
+}
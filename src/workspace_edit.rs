@@ -0,0 +1,168 @@
+//! Code actions and `workspace/applyEdit` often replace whole files or large ranges.
+//! lsp-diff diffs the `TextDocumentEdit`s in them against its copy of the document.
+
+use lsp_diff::LineHashes;
+use lsp_types::{TextEdit, Url};
+use serde_json::Value;
+
+use crate::document::Snapshots;
+use crate::format;
+
+/// Responses carrying a code action or a list of them.
+pub const CODE_ACTION_METHODS: &[&str] = &["textDocument/codeAction", "codeAction/resolve"];
+
+/// The server request whose params carry a `WorkspaceEdit`.
+pub const APPLY_EDIT: &str = "workspace/applyEdit";
+
+/// Rewrites the `WorkspaceEdit`s in a message from the server into minimal ones.
+/// `method` is the request's, or the method of the request the message answers.
+pub fn minimize(msg: &[u8], method: &str, snapshots: &Snapshots) -> Option<Vec<u8>> {
+    let mut msg: Value = serde_json::from_slice(msg).ok()?;
+    let minimized = if method == APPLY_EDIT {
+        minimize_edit(msg.get_mut("params")?.get_mut("edit")?, snapshots)
+    } else {
+        match msg.get_mut("result")? {
+            Value::Array(actions) => {
+                actions
+                    .iter_mut()
+                    .filter_map(|action| action.get_mut("edit"))
+                    // Every edit is minimized, `any` would stop at the first.
+                    .map(|edit| minimize_edit(edit, snapshots))
+                    .filter(|&minimized| minimized)
+                    .count()
+                    > 0
+            }
+            action => minimize_edit(action.get_mut("edit")?, snapshots),
+        }
+    };
+    if minimized {
+        serde_json::to_vec(&msg).ok()
+    } else {
+        None
+    }
+}
+
+/// Minimizes the `TextDocumentEdit`s of `edit` meant for the version of the document lsp-diff has.
+/// Returns whether any were.
+fn minimize_edit(edit: &mut Value, snapshots: &Snapshots) -> bool {
+    let changes = match edit.get_mut("documentChanges") {
+        Some(Value::Array(changes)) => changes,
+        _ => return false,
+    };
+    let mut minimized = false;
+    for change in changes {
+        // Creating, renaming and deleting files have a `kind`, text edits don't.
+        if change.get("kind").is_some() {
+            continue;
+        }
+        let document = &change["textDocument"];
+        let uri = document["uri"]
+            .as_str()
+            .and_then(|uri| Url::parse(uri).ok());
+        // Without a version there's no telling which text the edits are for.
+        let snapshot = match (uri, document["version"].as_u64()) {
+            (Some(uri), Some(version)) => snapshots.get(&uri, version),
+            _ => None,
+        };
+        let snapshot = match snapshot {
+            Some(snapshot) => snapshot,
+            None => continue,
+        };
+        // Annotated edits would lose their annotation.
+        let annotated = change["edits"]
+            .as_array()
+            .is_none_or(|edits| edits.iter().any(|edit| edit.get("annotationId").is_some()));
+        if annotated {
+            continue;
+        }
+        let edits: Vec<TextEdit> = match serde_json::from_value(change["edits"].clone()) {
+            Ok(edits) => edits,
+            Err(_) => continue,
+        };
+        if !edits
+            .iter()
+            .all(|edit| lsp_diff::contains(&snapshot.buffer, edit.range))
+        {
+            continue;
+        }
+        let hashes = LineHashes::new(&snapshot.buffer);
+        // Edits that don't check out are left as the server sent them.
+        let edits = format::minimize_edits(&snapshot.buffer, &hashes, &edits, snapshot.algorithm);
+        if let Some(Ok(edits)) = edits.map(serde_json::to_value) {
+            change["edits"] = edits;
+            minimized = true;
+        }
+    }
+    minimized
+}
+
+#[test]
+fn minimize_test() {
+    use crate::document::{Document, Documents};
+    use lsp_types::{Position, Range};
+    use serde_json::json;
+
+    let uri = Url::parse("file:///a.rs").unwrap();
+    let mut docs: Documents = Documents::new();
+    let item = serde_json::from_value(json!({
+        "uri": uri.as_str(), "languageId": "rust", "version": 3, "text": "fn main(){}\n"
+    }))
+    .unwrap();
+    docs.open(
+        uri.clone(),
+        Document::open(item, lsp_diff::Options::default()),
+    );
+
+    let apply_edit = |version: u64| {
+        let msg = json!({"jsonrpc": "2.0", "id": 1, "method": APPLY_EDIT, "params": {"edit": {
+            "documentChanges": [{"textDocument": {"uri": uri.as_str(), "version": version}, "edits": [{
+                "range": {"start": {"line": 0, "character": 0}, "end": {"line": 1, "character": 0}},
+                "newText": "fn main() {}\n"
+            }]}]
+        }}});
+        minimize(
+            &serde_json::to_vec(&msg).unwrap(),
+            APPLY_EDIT,
+            &docs.snapshots(),
+        )
+    };
+    let msg: Value = serde_json::from_slice(&apply_edit(3).unwrap()).unwrap();
+    assert_eq!(
+        msg["params"]["edit"]["documentChanges"][0]["edits"],
+        json!([{
+            "range": {"start": {"line": 0, "character": 9}, "end": {"line": 0, "character": 9}},
+            "newText": " "
+        }])
+    );
+    // Edits for another version are left alone.
+    assert!(apply_edit(2).is_none());
+    // Line breaks changing from `\r\n` to `\n` are replaced whole.
+    let crlf = Url::parse("file:///crlf.rs").unwrap();
+    let item = serde_json::from_value(json!({
+        "uri": crlf.as_str(), "languageId": "rust", "version": 1, "text": "fn main(){\r\n}\r\n"
+    }))
+    .unwrap();
+    docs.open(
+        crlf.clone(),
+        Document::open(item, lsp_diff::Options::default()),
+    );
+    let edit = TextEdit::new(
+        Range::new(Position::new(0, 0), Position::new(2, 0)),
+        "fn main() {\n}\n".to_owned(),
+    );
+    let msg = json!({"jsonrpc": "2.0", "id": 1, "method": APPLY_EDIT, "params": {"edit": {
+        "documentChanges": [{"textDocument": {"uri": crlf.as_str(), "version": 1}, "edits": [edit]}]
+    }}});
+    let msg = minimize(
+        &serde_json::to_vec(&msg).unwrap(),
+        APPLY_EDIT,
+        &docs.snapshots(),
+    )
+    .unwrap();
+    let msg: Value = serde_json::from_slice(&msg).unwrap();
+    let edits: Vec<TextEdit> =
+        serde_json::from_value(msg["params"]["edit"]["documentChanges"][0]["edits"].clone())
+            .unwrap();
+    let old = String::from("fn main(){\r\n}\r\n");
+    assert_eq!(lsp_diff::apply_edits(&old, &edits), "fn main() {\n}\n");
+}